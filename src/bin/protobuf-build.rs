@@ -0,0 +1,285 @@
+// Copyright 2019 PingCAP, Inc.
+
+//! Generate code from protos outside of a build script, e.g., to check the
+//! generated code into a repository.
+//!
+//! ```text
+//! protobuf-build generate --out src/protos [OPTIONS] FILES...
+//! protobuf-build check --out src/protos [OPTIONS] FILES...
+//! ```
+//!
+//! `generate` only writes, and removes, files generated by protobuf-build (as
+//! told by their header), and fails rather than overwrite any other file.
+//! `check` generates the code in memory and exits with status 1 if it differs
+//! from the generated files in the output directory. The headers, with the
+//! versions of protobuf-build and protoc, are not compared, so that checked-in
+//! code can be checked with other versions or the pure parser.
+//!
+//! Any other `--some-option VALUE` is passed to `Builder::set_option` as
+//! `some_option`, e.g., `--include proto`, `--package-name kvproto` or
+//! `--wrapper-options 'NO_MSG|NEW'`.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use protobuf_build::Builder;
+
+const USAGE: &str =
+    "usage: protobuf-build (generate|check) --out DIR [--codec CODEC] [--OPTION VALUE]... FILES...";
+
+const HEADER: &str = "// Generated by protobuf-build ";
+
+const CODEC: &str = if cfg!(feature = "prost-codec") {
+    "prost"
+} else {
+    "protobuf"
+};
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let command = args
+        .next()
+        .unwrap_or_else(|| usage_error("no command given"));
+    if command != "generate" && command != "check" {
+        usage_error(&format!("unknown command `{}`", command));
+    }
+
+    let mut builder = Builder::new();
+    let mut out_dir = None;
+    let mut files = Vec::new();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            files.push(arg);
+            continue;
+        }
+        let name = arg[2..].replace('-', "_");
+        let value = args
+            .next()
+            .unwrap_or_else(|| usage_error(&format!("missing value for `{}`", arg)));
+        match &*name {
+            "out" | "out_dir" => out_dir = Some(value),
+            "codec" if value != CODEC => {
+                eprintln!(
+                    "error: this binary was built for the {} codec, reinstall with the `{}-codec` feature",
+                    CODEC, value
+                );
+                process::exit(2);
+            }
+            "codec" => {}
+            _ => {
+                if let Err(e) = builder.set_option(&name, &value) {
+                    usage_error(&e);
+                }
+            }
+        }
+    }
+    let out_dir = out_dir.unwrap_or_else(|| usage_error("no output directory given"));
+    if files.is_empty() {
+        usage_error("no files given");
+    }
+    builder.files(&files);
+
+    if command == "generate" {
+        if let Err(e) = generate(&builder, Path::new(&out_dir)) {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let expected = builder.generate_to_memory();
+    let actual = read_generated_files(Path::new(&out_dir));
+    let stale = stale_files(&expected, &actual);
+    for (path, problem) in &stale {
+        eprintln!("{} {}", Path::new(&out_dir).join(path).display(), problem);
+    }
+    if !stale.is_empty() {
+        eprintln!(
+            "generated code in {} is out of date, run `protobuf-build generate`",
+            out_dir
+        );
+        process::exit(1);
+    }
+}
+
+// Writes the generated files to `out_dir` and removes the previously generated
+// files which are not generated any more.
+fn generate(builder: &Builder, out_dir: &Path) -> Result<(), String> {
+    let files = builder.generate_to_memory();
+    let existing = read_rs_files(out_dir);
+    let foreign: Vec<String> = files
+        .keys()
+        .filter(|path| matches!(existing.get(*path), Some(c) if !is_generated(c)))
+        .map(|path| out_dir.join(path).display().to_string())
+        .collect();
+    if !foreign.is_empty() {
+        return Err(format!(
+            "not overwriting {}, which protobuf-build didn't generate",
+            foreign.join(", ")
+        ));
+    }
+
+    for (path, content) in &existing {
+        if is_generated(content) && !files.contains_key(path) {
+            fs::remove_file(out_dir.join(path)).map_err(|e| e.to_string())?;
+        }
+    }
+    for (path, content) in &files {
+        if existing.get(path) == Some(content) {
+            continue;
+        }
+        let target = out_dir.join(path);
+        fs::create_dir_all(target.parent().unwrap()).map_err(|e| e.to_string())?;
+        fs::write(&target, content).map_err(|e| format!("{}: {}", target.display(), e))?;
+    }
+    Ok(())
+}
+
+// Generated files start with a header naming the versions of protobuf-build
+// and protoc, and a hash of the code.
+fn is_generated(content: &str) -> bool {
+    content.starts_with(HEADER)
+}
+
+// `content` without the header, which only records what generated it.
+fn without_header(content: &str) -> &str {
+    if !is_generated(content) {
+        return content;
+    }
+    match content.find('\n') {
+        Some(i) => &content[i..],
+        None => "",
+    }
+}
+
+// The generated files which differ between `expected` and `actual`, and how.
+fn stale_files<'a>(
+    expected: &'a BTreeMap<PathBuf, String>,
+    actual: &'a BTreeMap<PathBuf, String>,
+) -> Vec<(&'a Path, &'static str)> {
+    let mut stale = Vec::new();
+    for (path, content) in expected {
+        match actual.get(path) {
+            Some(c) if without_header(c) == without_header(content) => {}
+            Some(_) => stale.push((path.as_path(), "differs")),
+            None => stale.push((path.as_path(), "is missing")),
+        }
+    }
+    for path in actual.keys().filter(|p| !expected.contains_key(*p)) {
+        stale.push((path.as_path(), "is not generated"));
+    }
+    stale
+}
+
+// The generated files in `root`, see `read_rs_files`.
+fn read_generated_files(root: &Path) -> BTreeMap<PathBuf, String> {
+    read_rs_files(root)
+        .into_iter()
+        .filter(|(_, content)| is_generated(content))
+        .collect()
+}
+
+// Maps paths relative to `root` of `.rs` files to their contents; empty if
+// `root` doesn't exist.
+fn read_rs_files(root: &Path) -> BTreeMap<PathBuf, String> {
+    let mut result = BTreeMap::new();
+    if !root.exists() {
+        return result;
+    }
+    let mut dirs = vec![root.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        for e in fs::read_dir(&dir).expect("Couldn't read directory") {
+            let path = e.expect("Couldn't list file").path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension() == Some(OsStr::new("rs")) {
+                let content = fs::read_to_string(&path).expect("Couldn't read file");
+                result.insert(path.strip_prefix(root).unwrap().to_owned(), content);
+            }
+        }
+    }
+    result
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("error: {}\n{}", message, USAGE);
+    process::exit(2);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn nested() -> Builder {
+        let mut builder = Builder::new();
+        builder
+            .includes(&["tests/proto"])
+            .files(&["tests/proto/nested.proto"]);
+        builder
+    }
+
+    #[test]
+    fn test_generate() {
+        let out_dir = env::temp_dir().join(format!("protobuf-build-cli-{}", process::id()));
+        let _ = fs::remove_dir_all(&out_dir);
+        fs::create_dir_all(&out_dir).unwrap();
+        fs::write(out_dir.join("lib.rs"), "mod protos;\n").unwrap();
+        fs::write(out_dir.join("README"), "hand-written\n").unwrap();
+        fs::write(out_dir.join("old.rs"), format!("{}...\n", HEADER)).unwrap();
+        let builder = nested();
+
+        let generated = generate(&builder, &out_dir);
+        let files = read_rs_files(&out_dir);
+        let readme = fs::read_to_string(out_dir.join("README")).unwrap();
+        // A file which would be generated, but which protobuf-build didn't.
+        fs::write(out_dir.join("mod.rs"), "// mine\n").unwrap();
+        let overwritten = generate(&builder, &out_dir);
+        let mod_rs = fs::read_to_string(out_dir.join("mod.rs")).unwrap();
+        let _ = fs::remove_dir_all(&out_dir);
+
+        generated.unwrap();
+        assert_eq!(files[Path::new("lib.rs")], "mod protos;\n");
+        assert!(!files.contains_key(Path::new("old.rs")));
+        assert!(is_generated(&files[Path::new("mod.rs")]));
+        assert_eq!(readme, "hand-written\n");
+        assert!(overwritten.unwrap_err().contains("mod.rs"));
+        assert_eq!(mod_rs, "// mine\n");
+    }
+
+    // Code generated with other versions only differs in the headers.
+    #[test]
+    fn test_stale_files() {
+        let builder = nested();
+        let expected = builder.generate_to_memory();
+        let mut actual: BTreeMap<PathBuf, String> = expected
+            .iter()
+            .map(|(path, content)| {
+                let header = format!(
+                    "{}0.15.0 using libprotoc 3.1.0, with code hash 0123456789abcdef.",
+                    HEADER
+                );
+                (path.clone(), header + without_header(content))
+            })
+            .collect();
+        assert_eq!(stale_files(&expected, &actual), vec![]);
+
+        actual
+            .get_mut(Path::new("nested.rs"))
+            .unwrap()
+            .push_str("// changed\n");
+        actual.insert(PathBuf::from("old.rs"), format!("{}...\n", HEADER));
+        actual.remove(Path::new("mod.rs"));
+        assert_eq!(
+            stale_files(&expected, &actual),
+            vec![
+                (Path::new("mod.rs"), "is missing"),
+                (Path::new("nested.rs"), "differs"),
+                (Path::new("old.rs"), "is not generated"),
+            ]
+        );
+    }
+}
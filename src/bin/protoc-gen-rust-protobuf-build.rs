@@ -0,0 +1,153 @@
+// Copyright 2019 PingCAP, Inc.
+
+//! A protoc plugin which generates the same code as a `protobuf_build::Builder`
+//! in a build script, for use with protoc or buf outside of cargo.
+//!
+//! Builder options are passed as plugin parameters, e.g.,
+//! `--rust-protobuf-build_out=package_name=kvproto,black_list=gogoproto:src/protos`.
+//! See `Builder::set_option` for the available options.
+
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::panic;
+use std::path::Path;
+use std::process;
+
+use protobuf::plugin::{CodeGeneratorRequest, CodeGeneratorResponse, CodeGeneratorResponse_File};
+use protobuf::Message;
+use protobuf_build::Builder;
+
+fn main() {
+    let mut input = Vec::new();
+    io::stdin()
+        .read_to_end(&mut input)
+        .expect("Couldn't read request");
+    let request = CodeGeneratorRequest::parse_from_bytes(&input).expect("Couldn't parse request");
+    io::stdout()
+        .write_all(&respond(&request).write_to_bytes().unwrap())
+        .expect("Couldn't write response");
+}
+
+// The response to `request`, with the generated files or an error.
+fn respond(request: &CodeGeneratorRequest) -> CodeGeneratorResponse {
+    // Stdout is the response, even if protoc was run from a build script.
+    protobuf_build::warn_on_stderr();
+    let work_dir =
+        env::temp_dir().join(format!("protoc-gen-rust-protobuf-build-{}", process::id()));
+    // The library reports errors by panicking, turn that into an error response.
+    let result = panic::catch_unwind(|| generate(request, &work_dir));
+    let _ = fs::remove_dir_all(&work_dir);
+
+    let mut response = CodeGeneratorResponse::new();
+    match result {
+        Ok(Ok(files)) => response.set_file(files.into()),
+        Ok(Err(e)) => response.set_error(e),
+        Err(e) => response.set_error(
+            e.downcast_ref::<String>()
+                .cloned()
+                .or_else(|| e.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_else(|| "code generation failed".to_owned()),
+        ),
+    }
+    response
+}
+
+fn generate(
+    request: &CodeGeneratorRequest,
+    work_dir: &Path,
+) -> Result<Vec<CodeGeneratorResponse_File>, String> {
+    let desc_file = work_dir.join("request.desc");
+    fs::create_dir_all(work_dir).map_err(|e| e.to_string())?;
+
+    let mut desc = protobuf::descriptor::FileDescriptorSet::new();
+    desc.set_file(request.get_proto_file().to_vec().into());
+    fs::write(&desc_file, desc.write_to_bytes().unwrap()).map_err(|e| e.to_string())?;
+
+    let mut builder = Builder::new();
+    builder
+        .files(request.get_file_to_generate())
+        .descriptor_set_file(desc_file.display().to_string());
+    for param in request.get_parameter().split(',').filter(|p| !p.is_empty()) {
+        let mut kv = param.splitn(2, '=');
+        let name = kv.next().unwrap().trim();
+        let value = kv.next().unwrap_or("true").trim();
+        builder.set_option(name, value)?;
+    }
+    let files = builder
+        .generate_to_memory()
+        .into_iter()
+        .map(|(path, content)| {
+            let mut file = CodeGeneratorResponse_File::new();
+            file.set_name(path.to_str().unwrap().replace('\\', "/"));
+            file.set_content(content);
+            file
+        })
+        .collect();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use protobuf::descriptor::{
+        DescriptorProto, FieldDescriptorProto, FieldDescriptorProto_Label,
+        FieldDescriptorProto_Type, FileDescriptorProto,
+    };
+
+    // A request for `foo.proto`, with `message Bar { uint64 x = 1; }` in the
+    // package `foo`.
+    fn request(parameter: &str) -> CodeGeneratorRequest {
+        let mut field = FieldDescriptorProto::new();
+        field.set_name("x".to_owned());
+        field.set_json_name("x".to_owned());
+        field.set_number(1);
+        field.set_label(FieldDescriptorProto_Label::LABEL_OPTIONAL);
+        field.set_field_type(FieldDescriptorProto_Type::TYPE_UINT64);
+        let mut message = DescriptorProto::new();
+        message.set_name("Bar".to_owned());
+        message.mut_field().push(field);
+        let mut file = FileDescriptorProto::new();
+        file.set_name("foo.proto".to_owned());
+        file.set_package("foo".to_owned());
+        file.set_syntax("proto3".to_owned());
+        file.mut_message_type().push(message);
+
+        let mut request = CodeGeneratorRequest::new();
+        request.mut_file_to_generate().push("foo.proto".to_owned());
+        request.set_parameter(parameter.to_owned());
+        request.mut_proto_file().push(file);
+        request
+    }
+
+    #[test]
+    fn test_respond() {
+        let response = respond(&request("format=false"));
+        assert_eq!(response.get_error(), "");
+        let files: Vec<&str> = response.get_file().iter().map(|f| f.get_name()).collect();
+        assert!(files.contains(&"mod.rs"), "{:?}", files);
+        assert!(
+            response
+                .get_file()
+                .iter()
+                .any(|f| f.get_content().contains("pub struct Bar")),
+            "{:?}",
+            files
+        );
+
+        let response = respond(&request("no_such_option=1"));
+        assert!(response.get_file().is_empty());
+        assert_eq!(response.get_error(), "unknown option `no_such_option`");
+
+        // The library panics when a lint is an error.
+        let mut lint = request("lint=true,lint_level=FIELD_NAME_SNAKE_CASE=deny");
+        lint.mut_proto_file()[0].mut_message_type()[0].mut_field()[0].set_name("X".to_owned());
+        let response = respond(&lint);
+        assert!(response.get_file().is_empty());
+        assert!(
+            response.get_error().contains("FIELD_NAME_SNAKE_CASE"),
+            "{}",
+            response.get_error()
+        );
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright 2019 PingCAP, Inc.
+
+//! Reading the local configuration of a [buf](https://buf.build) workspace.
+//!
+//! Only local files are read. Dependencies are resolved against the modules of
+//! the workspace, i.e., they must be vendored into it.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::diagnostics::warn;
+use crate::Builder;
+
+/// `buf.work.yaml`.
+#[derive(Debug, Deserialize)]
+struct WorkConfig {
+    #[serde(default)]
+    directories: Vec<String>,
+}
+
+/// `buf.yaml`, version `v1` or `v2`.
+#[derive(Debug, Default, Deserialize)]
+struct ModuleConfig {
+    #[serde(default)]
+    version: Option<String>,
+    /// v1 only, the module's own name.
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    deps: Vec<String>,
+    /// v1 only.
+    #[serde(default)]
+    build: BuildConfig,
+    /// v2 only, paths are relative to the `buf.yaml`.
+    #[serde(default)]
+    modules: Vec<ModuleEntry>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct BuildConfig {
+    #[serde(default)]
+    excludes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModuleEntry {
+    path: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    excludes: Vec<String>,
+}
+
+/// A module of a workspace, i.e., a directory which is an include root.
+#[derive(Debug, PartialEq)]
+struct Module {
+    root: PathBuf,
+    name: Option<String>,
+    excludes: Vec<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+struct Workspace {
+    modules: Vec<Module>,
+    deps: Vec<String>,
+}
+
+impl Builder {
+    /// Take includes and files from the buf workspace in `dir`.
+    ///
+    /// `dir` should contain a `buf.work.yaml` or a `buf.yaml`. Every module of
+    /// the workspace becomes an include directory and all their `.proto` files,
+    /// except for excluded directories, are generated. Dependencies (`deps`)
+    /// must be vendored as modules of the workspace; other dependencies are
+    /// warned about and must be found via other includes.
+    pub fn buf_workspace(&mut self, dir: impl AsRef<Path>) -> &mut Self {
+        let dir = dir.as_ref();
+        let workspace = Workspace::read(dir)
+            .unwrap_or_else(|e| panic!("Invalid buf workspace {}: {}", dir.display(), e));
+
+        for dep in workspace.missing_deps() {
+            warn(format!(
+                "buf dependency `{}` is not a module of the workspace {}, \
+                 its files must be found in other includes",
+                dep,
+                dir.display()
+            ));
+        }
+
+        let mut files = Vec::new();
+        for module in &workspace.modules {
+            module
+                .proto_files(&module.root, &mut files)
+                .unwrap_or_else(|e| panic!("Couldn't read {}: {}", module.root.display(), e));
+        }
+        self.includes = workspace
+            .modules
+            .iter()
+            .map(|m| m.root.display().to_string())
+            .collect();
+        self.files = files.iter().map(|f| f.display().to_string()).collect();
+        self
+    }
+}
+
+impl Workspace {
+    fn read(dir: &Path) -> Result<Workspace, String> {
+        let work_file = dir.join("buf.work.yaml");
+        if work_file.exists() {
+            let work: WorkConfig = read_yaml(&work_file)?;
+            let mut workspace = Workspace::default();
+            for d in &work.directories {
+                let root = dir.join(d);
+                let buf_file = root.join("buf.yaml");
+                let config = if buf_file.exists() {
+                    read_yaml(&buf_file)?
+                } else {
+                    ModuleConfig::default()
+                };
+                if config.version.as_deref() == Some("v2") {
+                    return Err(format!(
+                        "{}: v2 modules can't be part of a buf.work.yaml workspace",
+                        buf_file.display()
+                    ));
+                }
+                workspace.add_v1(root, config);
+            }
+            return Ok(workspace);
+        }
+
+        let buf_file = dir.join("buf.yaml");
+        if !buf_file.exists() {
+            return Err("no buf.work.yaml or buf.yaml found".to_owned());
+        }
+        let config: ModuleConfig = read_yaml(&buf_file)?;
+        let mut workspace = Workspace::default();
+        match config.version.as_deref() {
+            Some("v2") => {
+                workspace.deps = config.deps;
+                if config.modules.is_empty() {
+                    workspace.modules.push(Module {
+                        root: dir.to_owned(),
+                        name: None,
+                        excludes: Vec::new(),
+                    });
+                }
+                for m in config.modules {
+                    workspace.modules.push(Module {
+                        root: dir.join(&m.path),
+                        name: m.name,
+                        excludes: m.excludes.iter().map(|e| dir.join(e)).collect(),
+                    });
+                }
+            }
+            Some("v1") | Some("v1beta1") | None => workspace.add_v1(dir.to_owned(), config),
+            Some(v) => return Err(format!("{}: unknown version `{}`", buf_file.display(), v)),
+        }
+        Ok(workspace)
+    }
+
+    fn add_v1(&mut self, root: PathBuf, config: ModuleConfig) {
+        self.deps.extend(config.deps);
+        self.modules.push(Module {
+            excludes: config.build.excludes.iter().map(|e| root.join(e)).collect(),
+            root,
+            name: config.name,
+        });
+    }
+
+    /// Dependencies which are not modules of the workspace.
+    fn missing_deps(&self) -> impl Iterator<Item = &String> {
+        self.deps.iter().filter(move |dep| {
+            // Dependencies may be pinned, e.g., `buf.build/acme/weather:v1`.
+            let name = dep.split(':').next().unwrap();
+            !self.modules.iter().any(|m| m.name.as_deref() == Some(name))
+        })
+    }
+}
+
+impl Module {
+    // Adds all `.proto` files under `dir` to `files`, in a stable order.
+    fn proto_files(&self, dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+        let mut paths = fs::read_dir(dir)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.sort();
+        for path in paths {
+            if self.excludes.contains(&path) {
+                continue;
+            }
+            if path.is_dir() {
+                self.proto_files(&path, files)?;
+            } else if path.extension() == Some(std::ffi::OsStr::new("proto")) {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_yaml<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    serde_yaml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write(root: &Path, path: &str, contents: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn test_work_v1() {
+        let scratch = crate::ScratchDir::new();
+        let root = scratch.0.clone();
+        write(
+            &root,
+            "buf.work.yaml",
+            "version: v1\ndirectories:\n  - proto\n  - vendor/google\n",
+        );
+        write(
+            &root,
+            "proto/buf.yaml",
+            "version: v1\ndeps:\n  - buf.build/googleapis/googleapis\n  - buf.build/acme/other:v2\nbuild:\n  excludes:\n    - skip\n",
+        );
+        write(&root, "proto/a.proto", "");
+        write(&root, "proto/sub/b.proto", "");
+        write(&root, "proto/skip/c.proto", "");
+        write(
+            &root,
+            "vendor/google/buf.yaml",
+            "version: v1\nname: buf.build/googleapis/googleapis\n",
+        );
+        write(&root, "vendor/google/api/http.proto", "");
+
+        let workspace = Workspace::read(&root).unwrap();
+        assert_eq!(
+            workspace.missing_deps().collect::<Vec<_>>(),
+            vec!["buf.build/acme/other:v2"]
+        );
+
+        let mut builder = Builder::new();
+        builder.buf_workspace(&root);
+        let root = root.display();
+        assert_eq!(
+            builder.includes,
+            vec![format!("{}/proto", root), format!("{}/vendor/google", root)]
+        );
+        assert_eq!(
+            builder.files,
+            vec![
+                format!("{}/proto/a.proto", root),
+                format!("{}/proto/sub/b.proto", root),
+                format!("{}/vendor/google/api/http.proto", root),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_v2() {
+        let scratch = crate::ScratchDir::new();
+        let root = &scratch.0;
+        write(
+            root,
+            "buf.yaml",
+            "version: v2\nmodules:\n  - path: proto\n    excludes:\n      - proto/skip\n  - path: vendor\n    name: buf.build/acme/dep\ndeps:\n  - buf.build/acme/dep\n",
+        );
+        write(root, "proto/a.proto", "");
+        write(root, "proto/skip/c.proto", "");
+        write(root, "vendor/d.proto", "");
+
+        let workspace = Workspace::read(root).unwrap();
+        assert_eq!(workspace.missing_deps().count(), 0);
+        assert_eq!(workspace.modules.len(), 2);
+        assert_eq!(workspace.modules[0].excludes, vec![root.join("proto/skip")]);
+    }
+}
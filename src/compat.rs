@@ -0,0 +1,484 @@
+// Copyright 2019 PingCAP, Inc.
+
+//! Checking that protos stay wire compatible with a baseline descriptor set,
+//! e.g., the `mod.desc` of the last release.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use protobuf::descriptor::{
+    DescriptorProto, EnumDescriptorProto, FieldDescriptorProto, FileDescriptorProto,
+    FileDescriptorSet,
+};
+use protobuf::Message;
+
+use crate::diagnostics::{report, Diagnostic, Severity};
+use crate::{Builder, Level};
+
+/// A field was removed without reserving its number.
+pub const FIELD_REMOVED: &str = "FIELD_REMOVED";
+/// A field has a different number.
+pub const FIELD_NUMBER_CHANGED: &str = "FIELD_NUMBER_CHANGED";
+/// A field has a different type.
+pub const FIELD_TYPE_CHANGED: &str = "FIELD_TYPE_CHANGED";
+/// A field changed between `optional`, `required` and `repeated`.
+pub const FIELD_LABEL_CHANGED: &str = "FIELD_LABEL_CHANGED";
+/// An enum value was removed without reserving its number.
+pub const ENUM_VALUE_REMOVED: &str = "ENUM_VALUE_REMOVED";
+/// A file has a different package.
+pub const PACKAGE_CHANGED: &str = "PACKAGE_CHANGED";
+/// A service method was removed, or its service.
+pub const METHOD_REMOVED: &str = "METHOD_REMOVED";
+
+pub(crate) const RULES: &[&str] = &[
+    FIELD_REMOVED,
+    FIELD_NUMBER_CHANGED,
+    FIELD_TYPE_CHANGED,
+    FIELD_LABEL_CHANGED,
+    ENUM_VALUE_REMOVED,
+    PACKAGE_CHANGED,
+    METHOD_REMOVED,
+];
+
+/// A change which breaks compatibility with the baseline.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct Violation {
+    pub rule: &'static str,
+    /// The file of the element, as named in the descriptor set.
+    pub file: String,
+    pub message: String,
+}
+
+impl Builder {
+    /// Check that the protos are wire compatible with the descriptor set in
+    /// `path` (e.g., a `mod.desc` from an earlier build, committed to the
+    /// repository). Incompatible changes are errors, unless their rule is
+    /// relaxed with `compatibility_level`.
+    ///
+    /// Only files, messages, enums and services in both sets are compared.
+    pub fn check_compatibility_against(&mut self, path: impl Into<String>) -> &mut Self {
+        self.compatibility_baseline = Some(path.into());
+        self
+    }
+
+    /// Set the level of a compatibility rule, e.g.,
+    /// `compatibility_level(compat::FIELD_LABEL_CHANGED, Level::Warn)`. The rules
+    /// are the constants of the `compat` module. All rules default to
+    /// `Level::Deny`.
+    pub fn compatibility_level(&mut self, rule: &str, level: Level) -> &mut Self {
+        assert!(
+            RULES.contains(&rule),
+            "unknown compatibility rule `{}`, expected one of {:?}",
+            rule,
+            RULES
+        );
+        self.compatibility_levels.push((rule.to_owned(), level));
+        self
+    }
+
+    // Reports violations of compatibility of `current` with
+    // `compatibility_baseline`.
+    pub(crate) fn check_compatibility(&self, current: &FileDescriptorSet) {
+        let path = match &self.compatibility_baseline {
+            Some(path) => path,
+            None => return,
+        };
+        let bytes = std::fs::read(path)
+            .unwrap_or_else(|e| panic!("Couldn't read baseline descriptor set {}: {}", path, e));
+        let baseline = FileDescriptorSet::parse_from_bytes(&bytes)
+            .unwrap_or_else(|e| panic!("Invalid baseline descriptor set {}: {}", path, e));
+
+        let diagnostics: Vec<_> = check(&baseline, current)
+            .into_iter()
+            .filter_map(|v| {
+                let severity = match self.rule_level(v.rule) {
+                    Level::Allow => return None,
+                    Level::Warn => Severity::Warning,
+                    Level::Deny => Severity::Error,
+                };
+                Some(Diagnostic {
+                    severity,
+                    location: Some(v.file),
+                    message: format!("{}: {}", v.rule, v.message),
+                })
+            })
+            .collect();
+        report(
+            &format!("protos are incompatible with {}", path),
+            &diagnostics,
+        );
+    }
+
+    fn rule_level(&self, rule: &str) -> Level {
+        self.compatibility_levels
+            .iter()
+            .rev()
+            .find(|(r, _)| r == rule)
+            .map_or(Level::Deny, |(_, level)| *level)
+    }
+}
+
+/// The changes from `baseline` to `current` which break compatibility.
+pub(crate) fn check(baseline: &FileDescriptorSet, current: &FileDescriptorSet) -> Vec<Violation> {
+    let current: HashMap<&str, &FileDescriptorProto> = current
+        .get_file()
+        .iter()
+        .map(|f| (f.get_name(), f))
+        .collect();
+    let mut checker = Checker {
+        violations: Vec::new(),
+        file: "",
+    };
+    for old in baseline.get_file() {
+        if let Some(new) = current.get(old.get_name()) {
+            checker.file = old.get_name();
+            checker.check_file(old, new);
+        }
+    }
+    checker.violations
+}
+
+struct Checker<'a> {
+    violations: Vec<Violation>,
+    file: &'a str,
+}
+
+impl Checker<'_> {
+    fn violation(&mut self, rule: &'static str, message: String) {
+        self.violations.push(Violation {
+            rule,
+            file: self.file.to_owned(),
+            message,
+        });
+    }
+
+    fn check_file(&mut self, old: &FileDescriptorProto, new: &FileDescriptorProto) {
+        if old.get_package() != new.get_package() {
+            self.violation(
+                PACKAGE_CHANGED,
+                format!(
+                    "package `{}` was renamed to `{}`",
+                    old.get_package(),
+                    new.get_package()
+                ),
+            );
+            // Everything else would be reported as changed, too.
+            return;
+        }
+        let prefix = old.get_package();
+        self.check_messages(prefix, old.get_message_type(), new.get_message_type());
+        self.check_enums(prefix, old.get_enum_type(), new.get_enum_type());
+
+        for service in old.get_service() {
+            let name = qualify(prefix, service.get_name());
+            let new_service = new
+                .get_service()
+                .iter()
+                .find(|s| s.get_name() == service.get_name());
+            for method in service.get_method() {
+                let exists = new_service
+                    .iter()
+                    .flat_map(|s| s.get_method())
+                    .any(|m| m.get_name() == method.get_name());
+                if !exists {
+                    self.violation(
+                        METHOD_REMOVED,
+                        format!("method `{}.{}` was removed", name, method.get_name()),
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_messages(&mut self, prefix: &str, old: &[DescriptorProto], new: &[DescriptorProto]) {
+        for message in old {
+            if let Some(new_message) = new.iter().find(|m| m.get_name() == message.get_name()) {
+                self.check_message(&qualify(prefix, message.get_name()), message, new_message);
+            }
+        }
+    }
+
+    fn check_message(&mut self, name: &str, old: &DescriptorProto, new: &DescriptorProto) {
+        for field in old.get_field() {
+            let field_name = format!("{}.{}", name, field.get_name());
+            let number = field.get_number();
+            if let Some(new_field) = new.get_field().iter().find(|f| f.get_number() == number) {
+                self.check_field(&field_name, field, new_field);
+            } else if let Some(new_field) = new
+                .get_field()
+                .iter()
+                .find(|f| f.get_name() == field.get_name())
+            {
+                self.violation(
+                    FIELD_NUMBER_CHANGED,
+                    format!(
+                        "field `{}` changed number from {} to {}",
+                        field_name,
+                        number,
+                        new_field.get_number()
+                    ),
+                );
+            } else if !new
+                .get_reserved_range()
+                .iter()
+                .any(|r| r.get_start() <= number && number < r.get_end())
+            {
+                self.violation(
+                    FIELD_REMOVED,
+                    format!(
+                        "field `{}` ({}) was removed without reserving its number",
+                        field_name, number
+                    ),
+                );
+            }
+        }
+        self.check_messages(name, old.get_nested_type(), new.get_nested_type());
+        self.check_enums(name, old.get_enum_type(), new.get_enum_type());
+    }
+
+    fn check_field(&mut self, name: &str, old: &FieldDescriptorProto, new: &FieldDescriptorProto) {
+        let (old_type, new_type) = (field_type(old), field_type(new));
+        if old_type != new_type {
+            self.violation(
+                FIELD_TYPE_CHANGED,
+                format!(
+                    "field `{}` ({}) changed type from {} to {}",
+                    name,
+                    old.get_number(),
+                    old_type,
+                    new_type
+                ),
+            );
+        }
+        if old.get_label() != new.get_label() {
+            self.violation(
+                FIELD_LABEL_CHANGED,
+                format!(
+                    "field `{}` ({}) changed from {} to {}",
+                    name,
+                    old.get_number(),
+                    enum_name(old.get_label(), "LABEL_"),
+                    enum_name(new.get_label(), "LABEL_")
+                ),
+            );
+        }
+    }
+
+    fn check_enums(
+        &mut self,
+        prefix: &str,
+        old: &[EnumDescriptorProto],
+        new: &[EnumDescriptorProto],
+    ) {
+        for e in old {
+            let new_enum = match new.iter().find(|n| n.get_name() == e.get_name()) {
+                Some(n) => n,
+                None => continue,
+            };
+            for value in e.get_value() {
+                let number = value.get_number();
+                // Enum reserved ranges are inclusive.
+                let kept = new_enum
+                    .get_value()
+                    .iter()
+                    .any(|v| v.get_number() == number)
+                    || new_enum
+                        .get_reserved_range()
+                        .iter()
+                        .any(|r| r.get_start() <= number && number <= r.get_end());
+                if !kept {
+                    self.violation(
+                        ENUM_VALUE_REMOVED,
+                        format!(
+                            "value `{}` ({}) of enum `{}` was removed without reserving its number",
+                            value.get_name(),
+                            number,
+                            qualify(prefix, e.get_name())
+                        ),
+                    );
+                }
+            }
+        }
+    }
+}
+
+// The full name of `name` in `prefix`, a package or message.
+pub(crate) fn qualify(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}.{}", prefix, name)
+    }
+}
+
+// The type of a field as written in a proto file, e.g., `uint64` or
+// `kvrpcpb.Context`.
+fn field_type(field: &FieldDescriptorProto) -> String {
+    if field.has_type_name() {
+        field.get_type_name().trim_start_matches('.').to_owned()
+    } else {
+        enum_name(field.get_field_type(), "TYPE_")
+    }
+}
+
+// `TYPE_UINT64` as `uint64`.
+fn enum_name(value: impl Debug, prefix: &str) -> String {
+    format!("{:?}", value)
+        .trim_start_matches(prefix)
+        .to_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{enum_type, field, message};
+    use protobuf::descriptor::{
+        DescriptorProto_ReservedRange, FieldDescriptorProto_Label, FieldDescriptorProto_Type,
+        MethodDescriptorProto, ServiceDescriptorProto,
+    };
+
+    // `kv.proto` with a message, an enum with `values` values and a service.
+    fn file(
+        package: &str,
+        fields: Vec<FieldDescriptorProto>,
+        values: usize,
+        methods: &[&str],
+    ) -> FileDescriptorSet {
+        let values: Vec<_> = (0..values).map(|n| format!("S{}", n)).collect();
+        let values: Vec<_> = values.iter().map(|v| v.as_str()).collect();
+
+        let mut service = ServiceDescriptorProto::new();
+        service.set_name("Kv".to_owned());
+        for name in methods {
+            let mut method = MethodDescriptorProto::new();
+            method.set_name(name.to_string());
+            service.mut_method().push(method);
+        }
+
+        let mut file =
+            crate::fixtures::file("kv.proto", package, &[], vec![message("Request", fields)]);
+        file.mut_enum_type().push(enum_type("State", &values));
+        file.mut_service().push(service);
+        let mut set = FileDescriptorSet::new();
+        set.mut_file().push(file);
+        set
+    }
+
+    fn rules(violations: &[Violation]) -> Vec<&str> {
+        violations.iter().map(|v| v.rule).collect()
+    }
+
+    #[test]
+    fn test_compatible() {
+        use FieldDescriptorProto_Type::*;
+
+        let old = file(
+            "kv",
+            vec![
+                field("key", 1, TYPE_BYTES),
+                field("version", 2, TYPE_UINT64),
+            ],
+            2,
+            &["Get"],
+        );
+        // Adding things and reserving removed numbers is fine.
+        let mut new = file(
+            "kv",
+            vec![
+                field("key", 1, TYPE_BYTES),
+                field("context", 3, TYPE_STRING),
+            ],
+            3,
+            &["Get", "Put"],
+        );
+        let mut reserved = DescriptorProto_ReservedRange::new();
+        reserved.set_start(2);
+        reserved.set_end(3);
+        new.mut_file()[0].mut_message_type()[0]
+            .mut_reserved_range()
+            .push(reserved);
+        assert_eq!(check(&old, &new), vec![]);
+    }
+
+    #[test]
+    fn test_incompatible() {
+        use FieldDescriptorProto_Type::*;
+
+        let old = file(
+            "kv",
+            vec![
+                field("key", 1, TYPE_BYTES),
+                field("version", 2, TYPE_UINT64),
+                field("ttl", 3, TYPE_UINT64),
+                field("gone", 4, TYPE_BOOL),
+            ],
+            2,
+            &["Get", "Put"],
+        );
+        let mut repeated = field("key", 1, TYPE_BYTES);
+        repeated.set_label(FieldDescriptorProto_Label::LABEL_REPEATED);
+        let new = file(
+            "kv",
+            vec![
+                repeated,
+                field("version", 2, TYPE_STRING),
+                field("ttl", 5, TYPE_UINT64),
+            ],
+            1,
+            &["Get"],
+        );
+        let violations = check(&old, &new);
+        assert_eq!(
+            rules(&violations),
+            vec![
+                FIELD_LABEL_CHANGED,
+                FIELD_TYPE_CHANGED,
+                FIELD_NUMBER_CHANGED,
+                FIELD_REMOVED,
+                ENUM_VALUE_REMOVED,
+                METHOD_REMOVED,
+            ]
+        );
+        assert_eq!(violations[1].file, "kv.proto");
+        assert_eq!(
+            violations[1].message,
+            "field `kv.Request.version` (2) changed type from uint64 to string"
+        );
+        assert_eq!(violations[5].message, "method `kv.Kv.Put` was removed");
+
+        let renamed = file("kv.v2", vec![], 0, &[]);
+        assert_eq!(rules(&check(&old, &renamed)), vec![PACKAGE_CHANGED]);
+    }
+
+    // Pruning removes the services of imports, which must not count as removed.
+    // The pure parser drops all services.
+    #[test]
+    #[cfg(not(feature = "pure-parser"))]
+    fn test_prune_types() {
+        let scratch = crate::ScratchDir::new();
+        std::fs::create_dir_all(&scratch.0).unwrap();
+        let baseline = scratch.0.join("baseline.desc");
+        std::fs::write(
+            &baseline,
+            file("kv", vec![], 1, &["Get"]).write_to_bytes().unwrap(),
+        )
+        .unwrap();
+
+        let files = Builder::new()
+            .add_proto_source(
+                "kv.proto",
+                "syntax = \"proto3\";\npackage kv;\n\
+                 message Request {}\nenum State { S0 = 0; }\n\
+                 service Kv { rpc Get(Request) returns (Request); }",
+            )
+            .add_proto_source(
+                "a.proto",
+                "syntax = \"proto3\";\nimport \"kv.proto\";\nmessage A { kv.Request r = 1; }",
+            )
+            .files(&["a.proto"])
+            .prune_types(true)
+            .check_compatibility_against(baseline.display().to_string())
+            .generate_to_memory();
+        assert!(files.contains_key(std::path::Path::new("mod.rs")));
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright 2019 PingCAP, Inc.
+
+use std::env::var;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::Builder;
+
+/// The contents of a `protobuf-build.toml` file. Every key is optional and
+/// corresponds to a `Builder` setter.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Proto files to generate, may be globs.
+    files: Option<Vec<String>>,
+    includes: Option<Vec<String>>,
+    include_google_protos: Option<bool>,
+    black_list: Option<Vec<String>>,
+    out_dir: Option<String>,
+    package_name: Option<String>,
+    re_export_services: Option<bool>,
+    /// `GenOpt` flag names.
+    wrapper_options: Option<Vec<String>>,
+    protoc_args: Option<Vec<String>>,
+    verbose: Option<bool>,
+}
+
+impl Builder {
+    /// Create a builder from `protobuf-build.toml` in `CARGO_MANIFEST_DIR`.
+    pub fn from_config() -> Builder {
+        let dir = var("CARGO_MANIFEST_DIR").expect("No CARGO_MANIFEST_DIR defined");
+        Builder::from_config_file(Path::new(&dir).join("protobuf-build.toml"))
+    }
+
+    /// Create a builder from a TOML configuration file. Relative paths in the
+    /// file are relative to the file's directory.
+    ///
+    /// The file and all inputs are registered with cargo so that the build
+    /// script is rerun when they change.
+    pub fn from_config_file(path: impl AsRef<Path>) -> Builder {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Couldn't read {}: {}", path.display(), e));
+        let config: Config = toml::from_str(&text)
+            .unwrap_or_else(|e| panic!("Invalid config file {}: {}", path.display(), e));
+
+        let mut builder = Builder::new();
+        builder.config_file = Some(path.display().to_string());
+        config
+            .apply(&mut builder, path.parent().unwrap_or_else(|| Path::new("")))
+            .unwrap_or_else(|e| panic!("Invalid config file {}: {}", path.display(), e));
+        builder
+    }
+}
+
+impl Config {
+    fn apply(self, builder: &mut Builder, dir: &Path) -> Result<(), String> {
+        let relative = |p: &str| dir.join(p).display().to_string();
+
+        if let Some(patterns) = self.files {
+            let mut files = Vec::new();
+            for pattern in patterns {
+                let matches = glob::glob(&relative(&pattern))
+                    .map_err(|e| format!("bad pattern in `files`: {}: {}", pattern, e))?;
+                let count = files.len();
+                for m in matches {
+                    files.push(m.map_err(|e| e.to_string())?.display().to_string());
+                }
+                if files.len() == count {
+                    return Err(format!("`files`: {} does not match any files", pattern));
+                }
+            }
+            builder.files(&files);
+        }
+        if let Some(includes) = self.includes {
+            let includes: Vec<_> = includes.iter().map(|i| relative(i)).collect();
+            builder.includes(&includes);
+        }
+        if self.include_google_protos == Some(true) {
+            builder.include_google_protos();
+        }
+        if let Some(black_list) = self.black_list {
+            builder.black_list(&black_list);
+        }
+        if let Some(out_dir) = self.out_dir {
+            builder.out_dir(relative(&out_dir));
+        }
+        if let Some(package_name) = self.package_name {
+            builder.package_name(package_name);
+        }
+        if let Some(args) = self.protoc_args {
+            for arg in args {
+                builder.protoc_arg(arg);
+            }
+        }
+        if let Some(verbose) = self.verbose {
+            builder.verbose(verbose);
+        }
+        // Codec options go through `set_option`, which rejects options for codecs
+        // which aren't enabled.
+        if let Some(re_export) = self.re_export_services {
+            builder.set_option("re_export_services", &re_export.to_string())?;
+        }
+        if let Some(opts) = self.wrapper_options {
+            builder
+                .set_option("wrapper_options", &opts.join("|"))
+                .map_err(|e| format!("`wrapper_options`: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Builder {
+    // Once a build script registers any path with cargo, only the registered
+    // paths are checked for changes, so register every input.
+    pub(crate) fn rerun_if_changed(&self) {
+        let config_file = match &self.config_file {
+            Some(f) => f,
+            None => return,
+        };
+        if var("OUT_DIR").is_err() {
+            return;
+        }
+        let inputs = self
+            .includes
+            .iter()
+            .chain(&self.files)
+            .filter(|p| Path::new(p).exists());
+        for path in std::iter::once(config_file).chain(inputs) {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unknown_key() {
+        let err = toml::from_str::<Config>("fiels = [\"a.proto\"]").unwrap_err();
+        assert!(err.to_string().contains("unknown field `fiels`"), "{}", err);
+    }
+
+    #[test]
+    fn test_apply() {
+        let config: Config = toml::from_str(
+            r#"
+includes = ["proto"]
+package_name = "kvproto"
+wrapper_options = ["NO_MSG", "NEW"]
+"#,
+        )
+        .unwrap();
+        let mut builder = Builder::new();
+        let result = config.apply(&mut builder, Path::new("dir"));
+        assert_eq!(builder.includes, vec!["dir/proto".to_owned()]);
+        assert_eq!(builder.package_name.as_deref(), Some("kvproto"));
+        if cfg!(feature = "prost-codec") {
+            result.unwrap();
+        } else {
+            assert!(result
+                .unwrap_err()
+                .contains("unknown option `wrapper_options`"));
+        }
+    }
+}
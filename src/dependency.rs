@@ -0,0 +1,168 @@
+// Copyright 2019 PingCAP, Inc.
+
+//! Sharing protos between crates through cargo's `links` metadata.
+//!
+//! A crate with `links = "kvproto"` exports the include directory of its protos
+//! with `export_includes`, which cargo passes to the build scripts of crates
+//! depending on it as `DEP_KVPROTO_INCLUDE`. Those use the protos with
+//! `include_from_dependency("kvproto")` and refer to the dependency's generated
+//! code rather than generating it again.
+
+use std::env::var;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use protobuf::descriptor::{FileDescriptorProto, FileDescriptorSet};
+
+use crate::Builder;
+
+impl Builder {
+    /// Copy the proto files used for generation (except for those from
+    /// dependencies) to `$OUT_DIR/include` and print it as `cargo:include`
+    /// metadata, for crates depending on this one to pass to
+    /// `include_from_dependency`. The crate must have a `links` key in its
+    /// `Cargo.toml`. Default is `false`.
+    pub fn export_includes(&mut self, export_includes: bool) -> &mut Self {
+        self.export_includes = export_includes;
+        self
+    }
+
+    /// Use the protos exported (with `export_includes`) by the dependency with
+    /// `links = "<links>"`, which are found via `DEP_<LINKS>_INCLUDE`.
+    ///
+    /// Their code is not generated, the generated code refers to the
+    /// dependency's instead. Its generated modules must be at the root of the
+    /// crate named `links`, e.g., `::kvproto::metapb` (with the same
+    /// `module_layout` for the protobuf codec).
+    pub fn include_from_dependency(&mut self, links: &str) -> &mut Self {
+        let name = format!("DEP_{}_INCLUDE", links.to_uppercase().replace('-', "_"));
+        let include = var(&name).unwrap_or_else(|_| {
+            panic!(
+                "{} is not set, `{}` must be a dependency with `links = \"{}\"` which calls `Builder::export_includes`",
+                name, links, links
+            )
+        });
+        self.includes.push(include.clone());
+        self.dependencies.push((links.replace('-', "_"), include));
+        self
+    }
+
+    /// The files of `desc` which are found in the include of a dependency, with
+    /// the name of the dependency's crate.
+    pub(crate) fn dependency_files<'a>(
+        &'a self,
+        desc: &'a FileDescriptorSet,
+    ) -> Vec<(&'a FileDescriptorProto, &'a str)> {
+        if self.dependencies.is_empty() {
+            return Vec::new();
+        }
+        let includes = self.include_dirs();
+        let dependencies: Vec<(PathBuf, &str)> = self
+            .dependencies
+            .iter()
+            .filter_map(|(name, include)| Some((fs::canonicalize(include).ok()?, &**name)))
+            .collect();
+        desc.get_file()
+            .iter()
+            .filter_map(|file| {
+                let include = source_include(&includes, file.get_name())?;
+                let (_, name) = dependencies.iter().find(|(d, _)| d == include)?;
+                Some((file, *name))
+            })
+            .collect()
+    }
+
+    // Copies the protos for `export_includes` and prints their directory.
+    pub(crate) fn write_exported_includes(&self) {
+        let out_dir =
+            var("OUT_DIR").expect("`export_includes` needs OUT_DIR, i.e., a build script");
+        let export_dir = Path::new(&out_dir).join("include");
+        self.copy_exported_includes(&export_dir);
+        println!("cargo:include={}", export_dir.display());
+    }
+
+    // Copies the protos for `export_includes` to `export_dir`.
+    fn copy_exported_includes(&self, export_dir: &Path) {
+        if export_dir.exists() {
+            fs::remove_dir_all(export_dir).unwrap();
+        }
+        fs::create_dir_all(export_dir).unwrap();
+
+        let desc = self.read_descriptor_set();
+        let includes = self.include_dirs();
+        let mut skipped: Vec<PathBuf> = self
+            .dependencies
+            .iter()
+            .filter_map(|(_, include)| fs::canonicalize(include).ok())
+            .collect();
+        // Every crate has the Google protos from `include_google_protos`.
+        skipped.extend(fs::canonicalize(format!("{}/include", env!("CARGO_MANIFEST_DIR"))).ok());
+        for file in desc.get_file() {
+            let include = match source_include(&includes, file.get_name()) {
+                Some(include) if !skipped.contains(include) => include,
+                // Files only in a `descriptor_set_file` can't be exported.
+                _ => continue,
+            };
+            let target = export_dir.join(file.get_name());
+            fs::create_dir_all(target.parent().unwrap()).unwrap();
+            fs::copy(include.join(file.get_name()), &target)
+                .unwrap_or_else(|e| panic!("Couldn't copy {}: {}", file.get_name(), e));
+        }
+    }
+}
+
+// The first of `includes` (from `include_dirs`) which contains the file `name`,
+// as protoc resolves it.
+fn source_include<'a>(includes: &'a [PathBuf], name: &str) -> Option<&'a PathBuf> {
+    includes.iter().find(|dir| dir.join(name).exists())
+}
+
+#[cfg(all(test, feature = "protobuf-codec"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_include_from_dependency() {
+        let scratch = crate::ScratchDir::new();
+        let export_dir = scratch.0.join("include");
+        // The dependency, which exports its protos but not the Google proto it
+        // imports.
+        let mut dependency = Builder::new();
+        dependency
+            .include_google_protos()
+            .includes(&["tests/proto"])
+            .add_proto_source(
+                "nested_time.proto",
+                "syntax = \"proto3\";\nimport \"google/protobuf/timestamp.proto\";\n\
+                 message Time { google.protobuf.Timestamp at = 1; }",
+            )
+            .files(&["tests/proto/nested.proto", "nested_time.proto"])
+            .export_includes(true);
+        let mut dependency = dependency.with_proto_sources(&scratch.0.join("sources"));
+        dependency.out_dir(scratch.0.join("out").display().to_string());
+        dependency.prep_out_dir();
+        dependency.descriptor_set();
+        dependency.copy_exported_includes(&export_dir);
+        let mut exported = Vec::new();
+        for e in fs::read_dir(&export_dir).unwrap() {
+            exported.push(e.unwrap().file_name().into_string().unwrap());
+        }
+        exported.sort();
+        assert_eq!(exported, vec!["nested.proto", "nested_time.proto"]);
+
+        std::env::set_var("DEP_PROTOBUF_BUILD_TEST_INCLUDE", &export_dir);
+        let files = Builder::new()
+            .include_from_dependency("protobuf-build-test")
+            .add_proto_source(
+                "events.proto",
+                "syntax = \"proto3\";\nimport \"nested.proto\";\n\
+                 message Events { repeated nested.Event events = 1; }",
+            )
+            .files(&["events.proto"])
+            .generate_to_memory();
+        let paths: Vec<_> = files.keys().map(|p| p.to_str().unwrap()).collect();
+        assert_eq!(paths, vec!["events.rs", "mod.rs"]);
+        assert!(files[Path::new("events.rs")].contains("super::nested::Event"));
+        assert!(files[Path::new("mod.rs")].contains("pub use ::protobuf_build_test::nested;"));
+    }
+}
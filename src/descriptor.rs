@@ -0,0 +1,698 @@
+// Copyright 2019 PingCAP, Inc.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "protobuf-codec")]
+use protobuf::descriptor::FileDescriptorProto;
+use protobuf::descriptor::{DescriptorProto, FileDescriptorSet};
+use protobuf::Message;
+
+#[cfg(not(feature = "pure-parser"))]
+use crate::diagnostics::Severity;
+use crate::diagnostics::{report, warn, Diagnostic};
+use crate::Builder;
+
+/// A file to generate: its path on disk and its name relative to an include
+/// directory, which is how protoc and the codecs refer to it.
+pub(crate) struct InputFile {
+    pub path: PathBuf,
+    pub name: String,
+}
+
+impl Builder {
+    /// The descriptor set for the files to generate and all their imports.
+    ///
+    /// The set is also written to `mod.desc` in the output directory. With
+    /// `prune_types`, it is written after pruning, and compatibility is checked
+    /// before, so that pruned imports don't count as removed.
+    pub(crate) fn descriptor_set(&self) -> FileDescriptorSet {
+        let desc_file = format!("{}/mod.desc", self.out_dir);
+        match &self.descriptor_set_file {
+            Some(path) => {
+                std::fs::copy(path, &desc_file)
+                    .unwrap_or_else(|e| panic!("Couldn't read descriptor set {}: {}", path, e));
+            }
+            None => self.compile_descriptor_set(&desc_file),
+        }
+
+        let mut desc = self.read_descriptor_set();
+        if self.descriptor_set_file.is_none() {
+            self.warn_shadowed_imports(&desc);
+        }
+        self.check_compatibility(&desc);
+        if self.prune_types {
+            prune_types(&mut desc, &self.root_names());
+            std::fs::write(&desc_file, desc.write_to_bytes().unwrap()).unwrap();
+        }
+        desc
+    }
+
+    /// The descriptor set written by `descriptor_set`.
+    pub(crate) fn read_descriptor_set(&self) -> FileDescriptorSet {
+        let desc_bytes = std::fs::read(format!("{}/mod.desc", self.out_dir)).unwrap();
+        let mut desc = FileDescriptorSet::new();
+        desc.merge_from_bytes(&desc_bytes).unwrap();
+        desc.check_initialized().unwrap();
+        desc
+    }
+
+    /// The include directories which exist, canonicalized. Directories nested in
+    /// another include come before it, so that the first include containing a
+    /// file is also the longest.
+    pub(crate) fn include_dirs(&self) -> Vec<PathBuf> {
+        let mut result: Vec<PathBuf> = Vec::new();
+        for include in &self.includes {
+            // The default includes often don't exist.
+            let dir = match fs::canonicalize(include) {
+                Ok(dir) => dir,
+                Err(_) => continue,
+            };
+            if result.contains(&dir) {
+                continue;
+            }
+            match result.iter().position(|r| dir.starts_with(r)) {
+                Some(i) => result.insert(i, dir),
+                None => result.push(dir),
+            }
+        }
+        result
+    }
+
+    /// Resolves the files to generate against `includes` (from `include_dirs`).
+    pub(crate) fn input_files(&self, includes: &[PathBuf]) -> Vec<InputFile> {
+        let mut result = Vec::new();
+        let mut diagnostics = Vec::new();
+        for file in &self.files {
+            let path = match fs::canonicalize(self.source_path(file)) {
+                Ok(path) => path,
+                Err(e) => {
+                    diagnostics.push(Diagnostic::error(format!("file {:?}: {}", file, e)));
+                    continue;
+                }
+            };
+            let i = match includes.iter().position(|i| path.starts_with(i)) {
+                Some(i) => i,
+                None => {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "file {:?} is not found in includes {:?}",
+                        file, self.includes
+                    )));
+                    continue;
+                }
+            };
+            let relative = path.strip_prefix(&includes[i]).unwrap();
+            let name = proto_name(relative);
+            if let Some(shadow) = includes[..i]
+                .iter()
+                .map(|dir| dir.join(relative))
+                .find(|p| p.exists())
+            {
+                diagnostics.push(Diagnostic::error(format!(
+                    "file {:?} is shadowed by {} (both are `{}`), reorder the includes or generate the latter",
+                    file,
+                    shadow.display(),
+                    name
+                )));
+                continue;
+            }
+            result.push(InputFile { path, name });
+        }
+        report("failed to resolve proto files", &diagnostics);
+        result
+    }
+
+    /// The names of `files`, as they appear in the descriptor set.
+    pub(crate) fn root_names(&self) -> Vec<String> {
+        // Files are already named relative to an include in a descriptor set.
+        if self.descriptor_set_file.is_some() {
+            return self.files.clone();
+        }
+        self.input_files(&self.include_dirs())
+            .into_iter()
+            .map(|f| f.name)
+            .collect()
+    }
+
+    /// The names of the files to generate, as they appear in `desc`: `files`
+    /// and, when set via `roots`, every file they import which is not on the
+    /// black list or from a dependency.
+    #[cfg(feature = "protobuf-codec")]
+    pub(crate) fn file_names(&self, desc: &FileDescriptorSet) -> Vec<String> {
+        let mut names = self.root_names();
+        if !self.generate_imports {
+            return names;
+        }
+        let mut files: HashMap<&str, &FileDescriptorProto> =
+            desc.get_file().iter().map(|f| (f.get_name(), f)).collect();
+        for (file, _) in self.dependency_files(desc) {
+            files.remove(file.get_name());
+        }
+        let mut i = 0;
+        while i < names.len() {
+            // Pruned files are gone from the set, and from its imports.
+            let deps = files
+                .get(&*names[i])
+                .map(|f| f.get_dependency())
+                .unwrap_or_default();
+            for dep in deps {
+                if !names.contains(dep)
+                    && files.contains_key(&**dep)
+                    && !self.black_list.iter().any(|b| dep.contains(b))
+                {
+                    names.push(dep.clone());
+                }
+            }
+            i += 1;
+        }
+        names
+    }
+
+    // Imports are resolved against the first include which contains them, which
+    // is surprising if another include has a different file with the same name.
+    fn warn_shadowed_imports(&self, desc: &FileDescriptorSet) {
+        let includes = self.include_dirs();
+        for file in desc.get_file() {
+            let found: Vec<_> = includes
+                .iter()
+                .map(|dir| dir.join(file.get_name()))
+                .filter(|p| p.exists())
+                .collect();
+            if found.len() > 1 {
+                warn(format!(
+                    "`{}` is found in more than one include, {} is used and shadows {}",
+                    file.get_name(),
+                    found[0].display(),
+                    found[1..]
+                        .iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+    }
+
+    #[cfg(not(feature = "pure-parser"))]
+    fn compile_descriptor_set(&self, desc_file: &str) {
+        use std::process::Command;
+
+        let includes = self.include_dirs();
+        let files = self.input_files(&includes);
+        let mut cmd = Command::new(crate::get_protoc());
+        for i in &includes {
+            cmd.arg(format!("-I{}", i.display()));
+        }
+        cmd.arg("--include_imports")
+            .arg("--include_source_info")
+            .arg("-o")
+            .arg(desc_file);
+        for arg in &self.protoc_args {
+            cmd.arg(arg);
+        }
+        for plugin in &self.protoc_plugins {
+            std::fs::create_dir_all(&plugin.out_dir).unwrap_or_else(|e| {
+                panic!("Couldn't create output directory {}: {}", plugin.out_dir, e)
+            });
+            cmd.arg(format!(
+                "--plugin=protoc-gen-{}={}",
+                plugin.name, plugin.path
+            ));
+            if plugin.params.is_empty() {
+                cmd.arg(format!("--{}_out={}", plugin.name, plugin.out_dir));
+            } else {
+                cmd.arg(format!(
+                    "--{}_out={}:{}",
+                    plugin.name, plugin.params, plugin.out_dir
+                ));
+            }
+        }
+        // protoc names each file after the first include which contains it, which
+        // is also the longest.
+        for f in &files {
+            cmd.arg(&f.path);
+        }
+        if self.verbose {
+            println!("executing {:?}", cmd);
+        }
+        let output = cmd
+            .output()
+            .unwrap_or_else(|e| panic!("failed to execute protoc: {}", e));
+
+        let mut diagnostics =
+            Diagnostic::parse_protoc_output(&String::from_utf8_lossy(&output.stdout));
+        diagnostics.extend(Diagnostic::parse_protoc_output(&String::from_utf8_lossy(
+            &output.stderr,
+        )));
+        if output.status.success() {
+            // Anything protoc has to say about a successful run is a warning.
+            diagnostics
+                .iter_mut()
+                .for_each(|d| d.severity = Severity::Warning);
+        } else {
+            // protoc only names the plugin's flag when it fails, e.g.
+            // `--doc_out: protoc-gen-doc: Plugin failed with status code 1.`
+            for d in &mut diagnostics {
+                for plugin in &self.protoc_plugins {
+                    let flag = format!("--{}_out: ", plugin.name);
+                    if d.message.starts_with(&flag) {
+                        d.message = format!(
+                            "protoc plugin `{}` ({}) failed: {}",
+                            plugin.name,
+                            plugin.path,
+                            &d.message[flag.len()..]
+                        );
+                    }
+                }
+            }
+            if diagnostics.iter().all(|d| d.severity != Severity::Error) {
+                diagnostics.push(Diagnostic::error(format!(
+                    "protoc exited with {}",
+                    output.status
+                )));
+            }
+        }
+        report("failed to generate descriptor set files", &diagnostics);
+    }
+
+    /// Parses the files to generate (and all their imports) in-process, without
+    /// invoking `protoc`.
+    ///
+    /// Source info (i.e., comments) is not available from the pure parser.
+    #[cfg(feature = "pure-parser")]
+    fn compile_descriptor_set(&self, desc_file: &str) {
+        assert!(
+            self.protoc_args.is_empty() && self.protoc_plugins.is_empty(),
+            "protoc arguments and plugins are not supported with the `pure-parser` feature"
+        );
+
+        // Like protoc, the parser names each file after the first include which
+        // contains it.
+        let includes = self.include_dirs();
+        let files = self.input_files(&includes);
+        let includes: Vec<&Path> = includes.iter().map(PathBuf::as_path).collect();
+        let parse = |paths: &[&Path]| {
+            std::panic::catch_unwind(|| {
+                protobuf_codegen_pure::parse_and_typecheck(&includes, paths)
+            })
+        };
+        let paths: Vec<&Path> = files.iter().map(|f| f.path.as_path()).collect();
+        let parsed = match parse(&paths) {
+            Ok(Ok(parsed)) => parsed,
+            Ok(Err(e)) => {
+                report("failed to parse proto files", &[parse_error(&e)]);
+                unreachable!()
+            }
+            // The parser panics on some errors, e.g., types which aren't
+            // defined, without naming the file, so look for the first file
+            // which fails on its own.
+            Err(panic) => {
+                let mut diagnostic = Diagnostic::error(
+                    panic
+                        .downcast_ref::<String>()
+                        .cloned()
+                        .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                        .unwrap_or_else(|| "the parser panicked".to_owned()),
+                );
+                diagnostic.location = files
+                    .iter()
+                    .find(|f| parse(&[f.path.as_path()]).is_err())
+                    .map(|f| f.name.clone());
+                report("failed to parse proto files", &[diagnostic]);
+                unreachable!()
+            }
+        };
+
+        let mut desc = FileDescriptorSet::new();
+        desc.set_file(parsed.file_descriptors.into());
+        std::fs::write(desc_file, desc.write_to_bytes().unwrap()).unwrap();
+    }
+}
+
+/// Removes the messages and enums which the types and services of `roots` don't
+/// use, directly or indirectly, from the other files of `desc`, and services from
+/// those files. Files which end up empty are removed, as are imports of them.
+///
+/// Types are kept or removed along with their top-level message, and extensions
+/// are always kept.
+fn prune_types(desc: &mut FileDescriptorSet, roots: &[String]) {
+    // Maps the full name of every type to the full name of its top-level type.
+    let mut owners = HashMap::new();
+    let mut messages = HashMap::new();
+    for file in desc.get_file() {
+        for message in file.get_message_type() {
+            let name = full_name(file.get_package(), message.get_name());
+            add_owners(message, &name, &name, &mut owners);
+            messages.insert(name, message);
+        }
+        for e in file.get_enum_type() {
+            let name = full_name(file.get_package(), e.get_name());
+            owners.insert(name.clone(), name);
+        }
+    }
+
+    let mut used = Vec::new();
+    for file in desc.get_file() {
+        for ext in file.get_extension() {
+            used.push(ext.get_type_name());
+            used.push(ext.get_extendee());
+        }
+        if !roots.iter().any(|r| r == file.get_name()) {
+            continue;
+        }
+        for message in file.get_message_type() {
+            used_types(message, &mut used);
+        }
+        for service in file.get_service() {
+            for method in service.get_method() {
+                used.push(method.get_input_type());
+                used.push(method.get_output_type());
+            }
+        }
+    }
+    let mut kept = HashSet::new();
+    while let Some(name) = used.pop() {
+        let owner = match owners.get(name) {
+            Some(owner) => owner,
+            // Scalars, or types missing from the set which protoc would reject.
+            None => continue,
+        };
+        if kept.insert(owner.clone()) {
+            if let Some(message) = messages.get(owner) {
+                used_types(message, &mut used);
+            }
+        }
+    }
+
+    let mut removed = HashSet::new();
+    for file in desc.mut_file().iter_mut() {
+        if roots.iter().any(|r| r == file.get_name()) {
+            continue;
+        }
+        let package = file.get_package().to_owned();
+        file.mut_message_type()
+            .retain(|m| kept.contains(&full_name(&package, m.get_name())));
+        file.mut_enum_type()
+            .retain(|e| kept.contains(&full_name(&package, e.get_name())));
+        file.mut_service().clear();
+        if file.get_message_type().is_empty()
+            && file.get_enum_type().is_empty()
+            && file.get_extension().is_empty()
+        {
+            removed.insert(file.get_name().to_owned());
+        }
+    }
+    desc.mut_file().retain(|f| !removed.contains(f.get_name()));
+    for file in desc.mut_file().iter_mut() {
+        let deps = file.take_dependency().into_vec();
+        let public: Vec<i32> = file.get_public_dependency().to_vec();
+        file.clear_public_dependency();
+        file.clear_weak_dependency();
+        for (i, dep) in deps.into_iter().enumerate() {
+            if removed.contains(&dep) {
+                continue;
+            }
+            if public.contains(&(i as i32)) {
+                let index = file.get_dependency().len() as i32;
+                file.mut_public_dependency().push(index);
+            }
+            file.mut_dependency().push(dep);
+        }
+    }
+}
+
+// The fully qualified name of a top-level type, as used in `type_name`.
+fn full_name(package: &str, name: &str) -> String {
+    match package {
+        "" => format!(".{}", name),
+        package => format!(".{}.{}", package, name),
+    }
+}
+
+fn add_owners(
+    message: &DescriptorProto,
+    name: &str,
+    owner: &str,
+    owners: &mut HashMap<String, String>,
+) {
+    owners.insert(name.to_owned(), owner.to_owned());
+    for nested in message.get_nested_type() {
+        add_owners(
+            nested,
+            &format!("{}.{}", name, nested.get_name()),
+            owner,
+            owners,
+        );
+    }
+    for e in message.get_enum_type() {
+        owners.insert(format!("{}.{}", name, e.get_name()), owner.to_owned());
+    }
+}
+
+// The types of the fields of `message` and its nested messages.
+fn used_types<'a>(message: &'a DescriptorProto, used: &mut Vec<&'a str>) {
+    for field in message.get_field().iter().chain(message.get_extension()) {
+        used.push(field.get_type_name());
+        // Empty for fields which aren't extensions.
+        used.push(field.get_extendee());
+    }
+    for nested in message.get_nested_type() {
+        used_types(nested, used);
+    }
+}
+
+// Proto names always use `/`.
+fn proto_name(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The pure parser only exposes the location of an error through its `Debug`
+/// output, so dig it out to give a `file:line:col: message` error. If that
+/// output changes, the error includes all of it rather than losing the
+/// location.
+#[cfg(feature = "pure-parser")]
+fn parse_error(e: &std::io::Error) -> Diagnostic {
+    use regex::Regex;
+
+    let debug = format!("{:?}", e);
+    let located = Regex::new(
+        r#"file: "([^"]*)", error: ParserErrorWithLocation\(ParserErrorWithLocation \{ error: (.*), line: (\d+), col: (\d+) \}\)"#,
+    )
+    .unwrap();
+    let unlocated = Regex::new(r#"file: "([^"]*)", error: ConvertError\((.*)\) \}"#).unwrap();
+    let mut diagnostic = Diagnostic::error(format!("{} ({})", e, debug));
+    if let Some(caps) = located.captures(&debug) {
+        diagnostic.location = Some(format!("{}:{}:{}", &caps[1], &caps[3], &caps[4]));
+        diagnostic.message = caps[2].to_owned();
+    } else if let Some(caps) = unlocated.captures(&debug) {
+        diagnostic.location = Some(caps[1].to_owned());
+        diagnostic.message = caps[2].to_owned();
+    }
+    diagnostic
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{field, file, message, message_field};
+    use protobuf::descriptor::{FieldDescriptorProto, FieldDescriptorProto_Type};
+
+    #[test]
+    fn test_longest_include() {
+        let root = std::env::temp_dir().join(format!("include-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("proto/sub")).unwrap();
+        fs::write(root.join("proto/sub/b.proto"), "").unwrap();
+        let root = fs::canonicalize(&root).unwrap();
+
+        let mut builder = Builder::new();
+        builder
+            .includes(&[
+                root.join("proto").display(),
+                root.join("proto/./sub").display(),
+            ])
+            .files(&[root.join("proto/sub/../sub/b.proto").display()]);
+        let includes = builder.include_dirs();
+        let files = builder.input_files(&includes);
+        let _ = fs::remove_dir_all(&root);
+        assert_eq!(includes, vec![root.join("proto/sub"), root.join("proto")]);
+        assert_eq!(files[0].name, "b.proto");
+        assert_eq!(files[0].path, root.join("proto/sub/b.proto"));
+    }
+
+    // The extra arguments and plugins are passed to protoc, here two plugins
+    // which keep the request they get, one of them given by arguments.
+    #[test]
+    #[cfg(all(unix, not(feature = "pure-parser")))]
+    fn test_protoc_plugins() {
+        use protobuf::plugin::CodeGeneratorRequest;
+        use std::os::unix::fs::PermissionsExt;
+
+        let scratch = crate::ScratchDir::new();
+        let dir = &scratch.0;
+        fs::create_dir_all(dir.join("b_out")).unwrap();
+        for name in &["a", "b"] {
+            let plugin = dir.join(format!("protoc-gen-{}", name));
+            fs::write(&plugin, "#!/bin/sh\ncat > \"$0.request\"\n").unwrap();
+            fs::set_permissions(&plugin, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let files = crate::fixtures::nested()
+            .protoc_plugin(
+                "a",
+                dir.join("protoc-gen-a").display().to_string(),
+                dir.join("a_out").display().to_string(),
+                "x=1",
+            )
+            .protoc_arg(format!(
+                "--plugin=protoc-gen-b={}/protoc-gen-b",
+                dir.display()
+            ))
+            .protoc_arg(format!("--b_out={}/b_out", dir.display()))
+            .generate_to_memory();
+        assert!(files.contains_key(Path::new("mod.rs")));
+
+        let request = |name: &str| {
+            let bytes = fs::read(dir.join(format!("protoc-gen-{}.request", name))).unwrap();
+            CodeGeneratorRequest::parse_from_bytes(&bytes).unwrap()
+        };
+        let a = request("a");
+        assert_eq!(a.get_file_to_generate(), ["nested.proto"]);
+        assert_eq!(a.get_parameter(), "x=1");
+        let b = request("b");
+        assert_eq!(b.get_file_to_generate(), ["nested.proto"]);
+        assert_eq!(b.get_parameter(), "");
+    }
+
+    // The error generating `content` as `bad.proto` with the pure parser.
+    #[cfg(feature = "pure-parser")]
+    fn parse_failure(content: &str) -> String {
+        let result = std::panic::catch_unwind(|| {
+            Builder::new()
+                .add_proto_source("bad.proto", content)
+                .files(&["bad.proto"])
+                .generate_to_memory()
+        });
+        let panic = result.unwrap_err();
+        panic.downcast_ref::<String>().unwrap().clone()
+    }
+
+    #[test]
+    #[cfg(feature = "pure-parser")]
+    fn test_parse_errors() {
+        let error = parse_failure("syntax = \"proto3\";\nmessage A {\n  uint64 x = ;\n}\n");
+        assert!(
+            error.starts_with("failed to parse proto files:\n  /"),
+            "{}",
+            error
+        );
+        assert!(error.contains("/bad.proto:3:"), "{}", error);
+
+        let error = parse_failure("syntax = \"proto3\";\nmessage A { Missing m = 1; }\n");
+        assert!(
+            error.contains("\n  bad.proto: couldn't find message or enum Missing"),
+            "{}",
+            error
+        );
+
+        // Errors after parsing only have a file.
+        let error = parse_failure(
+            "syntax = \"proto2\";\nmessage A { optional string s = 1 [default = 1]; }\n",
+        );
+        assert!(
+            error.contains("/bad.proto: DefaultValueIsNotStringLiteral"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_prune_types() {
+        let mut nested = message("B", vec![message_field("d", 1, ".p.D")]);
+        nested
+            .mut_nested_type()
+            .push(message("C", vec![message_field("e", 1, ".p.E")]));
+        let mut desc = FileDescriptorSet::new();
+        desc.set_file(
+            vec![
+                file(
+                    "a.proto",
+                    "",
+                    &["p.proto", "q.proto"],
+                    vec![message(
+                        "A",
+                        vec![
+                            message_field("c", 1, ".p.B.C"),
+                            field("x", 2, FieldDescriptorProto_Type::TYPE_UINT64),
+                        ],
+                    )],
+                ),
+                file(
+                    "p.proto",
+                    "p",
+                    &[],
+                    vec![
+                        nested,
+                        message("D", vec![]),
+                        message("E", vec![]),
+                        message("F", vec![]),
+                    ],
+                ),
+                file("q.proto", "q", &[], vec![message("G", vec![])]),
+            ]
+            .into(),
+        );
+
+        prune_types(&mut desc, &["a.proto".to_owned()]);
+        let names: Vec<_> = desc.get_file().iter().map(|f| f.get_name()).collect();
+        assert_eq!(names, vec!["a.proto", "p.proto"]);
+        assert_eq!(desc.get_file()[0].get_dependency(), &["p.proto".to_owned()]);
+        let messages: Vec<_> = desc.get_file()[1]
+            .get_message_type()
+            .iter()
+            .map(|m| m.get_name())
+            .collect();
+        assert_eq!(messages, vec!["B", "D", "E"]);
+    }
+
+    #[test]
+    fn test_prune_types_keeps_extendees() {
+        let mut extension = FieldDescriptorProto::new();
+        extension.set_name("label".to_owned());
+        extension.set_extendee(".q.G".to_owned());
+        let mut root = file("a.proto", "", &["q.proto"], vec![]);
+        root.mut_extension().push(extension.clone());
+        let mut nested = message("B", vec![]);
+        extension.set_extendee(".q.H".to_owned());
+        nested.mut_extension().push(extension);
+        root.mut_message_type().push(nested);
+        let mut desc = FileDescriptorSet::new();
+        desc.set_file(
+            vec![
+                root,
+                file(
+                    "q.proto",
+                    "q",
+                    &[],
+                    vec![
+                        message("G", vec![]),
+                        message("H", vec![]),
+                        message("I", vec![]),
+                    ],
+                ),
+            ]
+            .into(),
+        );
+
+        prune_types(&mut desc, &["a.proto".to_owned()]);
+        let messages: Vec<_> = desc.get_file()[1]
+            .get_message_type()
+            .iter()
+            .map(|m| m.get_name())
+            .collect();
+        assert_eq!(messages, vec!["G", "H"]);
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright 2019 PingCAP, Inc.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use regex::Regex;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Severity {
+    Warning,
+    Error,
+}
+
+/// A message about a proto file, e.g., from protoc.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Diagnostic {
+    pub severity: Severity,
+    // `file:line:col`, if the message is about a specific location.
+    pub location: Option<String>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            location: None,
+            message: message.into(),
+        }
+    }
+
+    /// Parse protoc's output, one diagnostic per non-empty line. Lines look like
+    /// `file:line:col: message` or `file:line:col: warning: message`, or are
+    /// free-form text.
+    #[cfg_attr(feature = "pure-parser", allow(dead_code))]
+    pub fn parse_protoc_output(output: &str) -> Vec<Diagnostic> {
+        let located = Regex::new(r"^([^:\s][^:]*:\d+:\d+): (.*)$").unwrap();
+        output
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(|line| {
+                let (location, message) = match located.captures(line) {
+                    Some(caps) => (Some(caps[1].to_owned()), caps[2].to_owned()),
+                    None => (None, line.to_owned()),
+                };
+                let lower = message.to_lowercase();
+                let (severity, message) = if lower.starts_with("warning:") {
+                    (
+                        Severity::Warning,
+                        message["warning:".len()..].trim().to_owned(),
+                    )
+                } else if lower.contains("warning:") {
+                    (Severity::Warning, message)
+                } else {
+                    (Severity::Error, message)
+                };
+                Diagnostic {
+                    severity,
+                    location,
+                    message,
+                }
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(location) = &self.location {
+            write!(f, "{}: ", location)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+static WARN_ON_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Send warnings to stderr, even when `OUT_DIR` is set. For the protoc plugin,
+/// whose stdout is its response, and which a build script may run.
+#[doc(hidden)]
+pub fn warn_on_stderr() {
+    WARN_ON_STDERR.store(true, Ordering::Relaxed);
+}
+
+/// Warn via cargo when running in a build script, otherwise on stderr (stdout
+/// may be in use, e.g., by a protoc plugin).
+pub(crate) fn warn(message: impl fmt::Display) {
+    if std::env::var_os("OUT_DIR").is_some() && !WARN_ON_STDERR.load(Ordering::Relaxed) {
+        println!("cargo:warning={}", message);
+    } else {
+        eprintln!("warning: {}", message);
+    }
+}
+
+/// Emit warnings to cargo and panic with all errors, if there are any.
+pub(crate) fn report(context: &str, diagnostics: &[Diagnostic]) {
+    let mut errors = String::new();
+    for d in diagnostics {
+        match d.severity {
+            Severity::Warning => warn(d),
+            Severity::Error => errors += &format!("\n  {}", d),
+        }
+    }
+    if !errors.is_empty() {
+        panic!("{}:{}", context, errors);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_protoc_output() {
+        let output = "\
+proto/a.proto:3:1: warning: Import proto/b.proto is unused.
+proto/a.proto:7:5: \"Foo\" is not defined.
+
+--doc_out: protoc-gen-doc: Plugin failed with status code 1.
+";
+        assert_eq!(
+            Diagnostic::parse_protoc_output(output),
+            vec![
+                Diagnostic {
+                    severity: Severity::Warning,
+                    location: Some("proto/a.proto:3:1".to_owned()),
+                    message: "Import proto/b.proto is unused.".to_owned(),
+                },
+                Diagnostic {
+                    severity: Severity::Error,
+                    location: Some("proto/a.proto:7:5".to_owned()),
+                    message: "\"Foo\" is not defined.".to_owned(),
+                },
+                Diagnostic::error("--doc_out: protoc-gen-doc: Plugin failed with status code 1."),
+            ]
+        );
+    }
+}
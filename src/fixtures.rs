@@ -0,0 +1,71 @@
+// Copyright 2019 PingCAP, Inc.
+
+//! Builders and descriptors for tests, the latter for tests which check
+//! descriptors without going through protoc.
+
+use protobuf::descriptor::{
+    DescriptorProto, EnumDescriptorProto, EnumValueDescriptorProto, FieldDescriptorProto,
+    FieldDescriptorProto_Label, FieldDescriptorProto_Type, FileDescriptorProto,
+};
+
+/// A builder for `tests/proto/nested.proto`, which most tests generate.
+#[cfg(any(feature = "protobuf-codec", not(feature = "pure-parser")))]
+pub(crate) fn nested() -> crate::Builder {
+    let mut builder = crate::Builder::new();
+    builder
+        .includes(&["tests/proto"])
+        .files(&["tests/proto/nested.proto"]);
+    builder
+}
+
+/// An optional field of a scalar type.
+pub(crate) fn field(name: &str, number: i32, t: FieldDescriptorProto_Type) -> FieldDescriptorProto {
+    let mut field = FieldDescriptorProto::new();
+    field.set_name(name.to_owned());
+    field.set_number(number);
+    field.set_field_type(t);
+    field.set_label(FieldDescriptorProto_Label::LABEL_OPTIONAL);
+    field
+}
+
+/// An optional field of a message type, given by its fully qualified name
+/// (e.g., `.p.B`).
+pub(crate) fn message_field(name: &str, number: i32, type_name: &str) -> FieldDescriptorProto {
+    let mut field = field(name, number, FieldDescriptorProto_Type::TYPE_MESSAGE);
+    field.set_type_name(type_name.to_owned());
+    field
+}
+
+pub(crate) fn message(name: &str, fields: Vec<FieldDescriptorProto>) -> DescriptorProto {
+    let mut message = DescriptorProto::new();
+    message.set_name(name.to_owned());
+    message.set_field(fields.into());
+    message
+}
+
+/// An enum with the given values, numbered from zero.
+pub(crate) fn enum_type(name: &str, values: &[&str]) -> EnumDescriptorProto {
+    let mut e = EnumDescriptorProto::new();
+    e.set_name(name.to_owned());
+    for (number, name) in values.iter().enumerate() {
+        let mut value = EnumValueDescriptorProto::new();
+        value.set_name(name.to_string());
+        value.set_number(number as i32);
+        e.mut_value().push(value);
+    }
+    e
+}
+
+pub(crate) fn file(
+    name: &str,
+    package: &str,
+    deps: &[&str],
+    messages: Vec<DescriptorProto>,
+) -> FileDescriptorProto {
+    let mut file = FileDescriptorProto::new();
+    file.set_name(name.to_owned());
+    file.set_package(package.to_owned());
+    file.set_dependency(deps.iter().map(|d| d.to_string()).collect());
+    file.set_message_type(messages.into());
+    file
+}
@@ -0,0 +1,271 @@
+// Copyright 2019 PingCAP, Inc.
+
+//! Custom code generation from the descriptors of the protos, registered with
+//! `Builder::generator`.
+//!
+//! Each `GeneratedFile` belongs to a proto file and is included in the module
+//! of its code, alongside the wrappers with Prost, so it can refer to the
+//! file's types by name.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use protobuf::descriptor::{DescriptorProto, FileDescriptorProto, FileDescriptorSet};
+
+use crate::compat::qualify;
+use crate::diagnostics::{report, Diagnostic};
+use crate::Builder;
+
+/// Generates code from the descriptors of the protos.
+pub trait Generator {
+    /// The files to write for the protos in `ctx`, which may be none. Each name
+    /// must be a file name (without directories) ending in `.rs`, unique among
+    /// all generated files in its module, or generation fails.
+    fn generate(&self, ctx: &GenContext) -> Vec<GeneratedFile>;
+}
+
+/// The codec which generates the code for the protos.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Codec {
+    Protobuf,
+    Prost,
+}
+
+/// What a `Generator` generates from.
+pub struct GenContext<'a> {
+    descriptor_set: &'a FileDescriptorSet,
+    files: Vec<String>,
+    modules: HashMap<String, Vec<String>>,
+    rust_paths: HashMap<String, String>,
+}
+
+impl<'a> GenContext<'a> {
+    /// All proto files, including imports.
+    pub fn descriptor_set(&self) -> &'a FileDescriptorSet {
+        self.descriptor_set
+    }
+
+    /// The proto files which have a module of generated code, i.e., which a
+    /// `GeneratedFile` can belong to.
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    pub fn codec(&self) -> Codec {
+        if cfg!(feature = "protobuf-codec") {
+            Codec::Protobuf
+        } else {
+            Codec::Prost
+        }
+    }
+
+    /// The Rust path of a message or enum, given by its fully qualified proto
+    /// name (e.g., `.kvrpcpb.Context`), relative to the module which includes
+    /// `mod.rs` (e.g., `kvrpcpb::Context`). Types of dependencies (see
+    /// `Builder::include_from_dependency`) may have absolute paths.
+    pub fn rust_path(&self, proto_type: &str) -> Option<&str> {
+        self.rust_paths.get(proto_type).map(|p| &**p)
+    }
+
+    /// The Rust path of a message or enum for code in a `GeneratedFile` of
+    /// `proto_file`, e.g., `super::kvrpcpb::Context`.
+    pub fn rust_path_from(&self, proto_file: &str, proto_type: &str) -> Option<String> {
+        let path = self.rust_path(proto_type)?;
+        if path.starts_with("::") {
+            return Some(path.to_owned());
+        }
+        let depth = self.modules.get(proto_file)?.len();
+        Some(format!("{}{}", "super::".repeat(depth), path))
+    }
+}
+
+/// A file of Rust code from a `Generator`.
+pub struct GeneratedFile {
+    /// The proto file (as named in the descriptor set) in whose module the
+    /// code is included, one of `GenContext::files`.
+    pub proto_file: String,
+    /// The name of the file, e.g., `metrics_kvrpcpb.rs`. It must not be the
+    /// name of another generated file.
+    pub name: String,
+    pub content: String,
+}
+
+/// A file written for a `GeneratedFile`.
+pub(crate) struct Included {
+    /// The path of the file, relative to the output directory.
+    pub path: PathBuf,
+    /// The module which includes it, relative to the output directory.
+    pub module: Vec<String>,
+}
+
+impl Builder {
+    /// Run `generator` after the codec, for both codecs.
+    pub fn generator(&mut self, generator: impl Generator + Send + Sync + 'static) -> &mut Self {
+        self.generators.push(Arc::new(generator));
+        self
+    }
+
+    // Runs the generators and writes their files, returning them.
+    pub(crate) fn run_generators(&self) -> Vec<Included> {
+        if self.generators.is_empty() {
+            return Vec::new();
+        }
+        let desc = self.read_descriptor_set();
+        let modules = self.generated_modules(&desc);
+        let mut files: Vec<String> = modules.keys().cloned().collect();
+        files.sort();
+        let mut rust_paths = HashMap::new();
+        for file in desc.get_file() {
+            if let Some(module) = self.type_module(&desc, file, &modules) {
+                add_rust_paths(file, &module, &mut rust_paths);
+            }
+        }
+        let ctx = GenContext {
+            descriptor_set: &desc,
+            files,
+            modules,
+            rust_paths,
+        };
+
+        let mut included: Vec<Included> = Vec::new();
+        let mut diagnostics = Vec::new();
+        for generated in self.generators.iter().flat_map(|g| g.generate(&ctx)) {
+            let module = match ctx.modules.get(&generated.proto_file) {
+                Some(module) => module.clone(),
+                None => {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "`{}` is generated for {}, which has no module of generated code",
+                        generated.name, generated.proto_file
+                    )));
+                    continue;
+                }
+            };
+            let mut path = self.included_dir(&module);
+            path.push(&generated.name);
+            let valid_name =
+                generated.name.ends_with(".rs") && !generated.name.contains(['/', '\\']);
+            if !valid_name || Path::new(&self.out_dir).join(&path).exists() {
+                diagnostics.push(Diagnostic::error(format!(
+                    "`{}` (for {}) is not a file name ending in `.rs`, or is already generated",
+                    generated.name, generated.proto_file
+                )));
+                continue;
+            }
+            let target = Path::new(&self.out_dir).join(&path);
+            fs::write(&target, generated.content).expect("Could not write source file");
+            included.push(Included { path, module });
+        }
+        report("custom generators failed", &diagnostics);
+        self.include_generated(&included);
+        included
+    }
+}
+
+// Adds the Rust paths of the messages and enums of `file`, whose types are in
+// `module`.
+fn add_rust_paths(
+    file: &FileDescriptorProto,
+    module: &[String],
+    rust_paths: &mut HashMap<String, String>,
+) {
+    fn add_message(
+        prefix: &str,
+        nesting: &[&str],
+        message: &DescriptorProto,
+        module: &[String],
+        rust_paths: &mut HashMap<String, String>,
+    ) {
+        let name = qualify(prefix, message.get_name());
+        rust_paths.insert(
+            format!(".{}", name),
+            type_path(module, nesting, message.get_name()),
+        );
+        let mut nested_nesting = nesting.to_vec();
+        nested_nesting.push(message.get_name());
+        for nested in message.get_nested_type() {
+            add_message(&name, &nested_nesting, nested, module, rust_paths);
+        }
+        for e in message.get_enum_type() {
+            rust_paths.insert(
+                format!(".{}.{}", name, e.get_name()),
+                type_path(module, &nested_nesting, e.get_name()),
+            );
+        }
+    }
+
+    let package = file.get_package();
+    for message in file.get_message_type() {
+        add_message(package, &[], message, module, rust_paths);
+    }
+    for e in file.get_enum_type() {
+        rust_paths.insert(
+            format!(".{}", qualify(package, e.get_name())),
+            type_path(module, &[], e.get_name()),
+        );
+    }
+}
+
+// rust-protobuf names nested types `Outer_Inner`.
+#[cfg(feature = "protobuf-codec")]
+fn type_path(module: &[String], nesting: &[&str], name: &str) -> String {
+    let mut names = nesting.to_vec();
+    names.push(name);
+    format!("{}::{}", module.join("::"), names.join("_"))
+}
+
+// Prost puts nested types in a module named after the outer message.
+#[cfg(not(feature = "protobuf-codec"))]
+fn type_path(module: &[String], nesting: &[&str], name: &str) -> String {
+    use crate::prost_impl::{to_snake, to_upper_camel};
+
+    let mut path = module.to_vec();
+    path.extend(nesting.iter().map(|outer| to_snake(outer)));
+    path.push(to_upper_camel(name));
+    path.join("::")
+}
+
+#[cfg(all(test, feature = "protobuf-codec"))]
+mod test {
+    use super::*;
+
+    struct Labels;
+
+    impl Generator for Labels {
+        fn generate(&self, ctx: &GenContext) -> Vec<GeneratedFile> {
+            assert_eq!(ctx.codec(), Codec::Protobuf);
+            assert_eq!(
+                ctx.rust_path(".nested.Event.Row"),
+                Some("nested::Event_Row")
+            );
+            assert_eq!(
+                ctx.rust_path(".nested.Event.Row.OpType"),
+                Some("nested::Event_Row_OpType")
+            );
+            ctx.files()
+                .iter()
+                .map(|file| GeneratedFile {
+                    proto_file: file.clone(),
+                    name: "labels_nested.rs".to_owned(),
+                    content: format!(
+                        "impl {} {{ pub const LABEL: &'static str = \"event\"; }}\n",
+                        ctx.rust_path_from(file, ".nested.Event").unwrap()
+                    ),
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_generator() {
+        let files = crate::fixtures::nested()
+            .generator(Labels)
+            .generate_to_memory();
+        let paths: Vec<_> = files.keys().map(|p| p.to_str().unwrap()).collect();
+        assert_eq!(paths, vec!["labels_nested.rs", "mod.rs", "nested.rs"]);
+        assert!(files[Path::new("labels_nested.rs")].contains("impl super::nested::Event {"));
+        assert!(files[Path::new("nested.rs")].contains("include!(\"labels_nested.rs\");"));
+        assert!(!files[Path::new("mod.rs")].contains("labels_nested"));
+    }
+}
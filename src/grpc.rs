@@ -0,0 +1,334 @@
+// Copyright 2019 PingCAP, Inc.
+
+//! Removing the grpcio code which `Builder::grpc_services` doesn't select.
+//!
+//! grpcio-compiler always generates clients and servers, and with Prost it
+//! generates every service, so the rest is removed from its output by a fixup.
+//! The fixup only removes items with the shapes of grpcio's code, as with Prost
+//! they share a file with the messages.
+//! With rust-protobuf, services which aren't selected are also left out of the
+//! descriptors grpcio-compiler gets, so that files without any selected
+//! services aren't generated.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use proc_macro2::{TokenStream, TokenTree};
+use quote::ToTokens;
+use syn::{Item, Type};
+
+use crate::post_process::PostProcessor;
+use crate::Builder;
+
+impl Builder {
+    /// A fixup which removes the code for services and sides which aren't
+    /// selected, given the services (as fully qualified names) in each file
+    /// with grpcio code (by path relative to the output directory).
+    pub(crate) fn service_fixup(
+        &self,
+        files: HashMap<PathBuf, Vec<String>>,
+    ) -> Option<PostProcessor> {
+        let filter = self.grpc_services.clone();
+        if filter.clients && filter.servers && filter.patterns.iter().any(|p| p == "*") {
+            return None;
+        }
+
+        Some(Arc::new(move |path: &Path, content: &mut String| {
+            let services = match files.get(path) {
+                Some(services) => services,
+                None => return,
+            };
+            // The sides kept for each service, by normalized name.
+            let kept: HashMap<String, (bool, bool)> = services
+                .iter()
+                .map(|s| {
+                    let selected = filter.selects(s);
+                    let name = s.rsplit('.').next().unwrap();
+                    (
+                        normalize(name),
+                        (selected && filter.clients, selected && filter.servers),
+                    )
+                })
+                .collect();
+
+            let mut file = syn::parse_file(content)
+                .unwrap_or_else(|e| panic!("Couldn't parse {}: {}", path.display(), e));
+            let grpcio = GrpcioItems::new(&file.items);
+            let len = file.items.len();
+            file.items.retain(|item| match grpcio.service_side(item) {
+                Some((service, side)) => {
+                    kept.get(&service)
+                        .iter()
+                        .all(|(client, server)| match side {
+                            Side::Client => *client,
+                            Side::Server => *server,
+                        })
+                }
+                None => true,
+            });
+            if file.items.len() == len {
+                return;
+            }
+            remove_unused_methods(&mut file.items);
+            // Comments are lost, and the formatting with them.
+            *content = file.into_token_stream().to_string();
+        }))
+    }
+}
+
+enum Side {
+    Client,
+    Server,
+}
+
+// The names of the items which grpcio-compiler generates in a file: client
+// structs, which wrap a `::grpcio::Client`, and the services of `create_*`
+// functions, which return a `::grpcio::Service`. Messages can have the same
+// names, so client structs and their `impl`s are also checked on their own.
+struct GrpcioItems {
+    clients: HashSet<String>,
+    servers: HashSet<String>,
+}
+
+impl GrpcioItems {
+    fn new(items: &[Item]) -> GrpcioItems {
+        let mut result = GrpcioItems {
+            clients: HashSet::new(),
+            servers: HashSet::new(),
+        };
+        for item in items {
+            match item {
+                Item::Struct(s) if is_client(s) => {
+                    result.clients.insert(s.ident.to_string());
+                }
+                Item::Fn(f) => match (
+                    f.sig.ident.to_string().strip_prefix("create_"),
+                    &f.sig.output,
+                ) {
+                    (Some(service), syn::ReturnType::Type(_, ty))
+                        if is_grpcio_type(ty, "Service") =>
+                    {
+                        result.servers.insert(normalize(service));
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        result
+    }
+
+    // The service (by normalized name) and side which grpcio-compiler generates
+    // `item` for: `FooClient` and its `impl` for clients, and the trait `Foo`
+    // and `create_foo` for servers.
+    fn service_side(&self, item: &Item) -> Option<(String, Side)> {
+        let client = |ident: &syn::Ident| {
+            let name = ident.to_string();
+            if !self.clients.contains(&name) {
+                return None;
+            }
+            name.strip_suffix("Client")
+                .map(|service| (normalize(service), Side::Client))
+        };
+        let server = |name: &str| {
+            let service = normalize(name);
+            if self.servers.contains(&service) {
+                Some((service, Side::Server))
+            } else {
+                None
+            }
+        };
+        match item {
+            Item::Struct(s) if is_client(s) => client(&s.ident),
+            Item::Impl(i) if i.trait_.is_none() && mentions_grpcio(i) => match &*i.self_ty {
+                Type::Path(p) => client(&p.path.segments.last()?.ident),
+                _ => None,
+            },
+            Item::Trait(t) => server(&t.ident.to_string()),
+            Item::Fn(f) => server(f.sig.ident.to_string().strip_prefix("create_")?),
+            _ => None,
+        }
+    }
+}
+
+// Whether `s` is grpcio-compiler's `FooClient`.
+fn is_client(s: &syn::ItemStruct) -> bool {
+    s.ident.to_string().ends_with("Client")
+        && s.fields.iter().any(|f| {
+            f.ident.as_ref().map_or(false, |i| i == "client") && is_grpcio_type(&f.ty, "Client")
+        })
+}
+
+fn mentions_grpcio(item: &syn::ItemImpl) -> bool {
+    let mut idents = HashSet::new();
+    add_idents(item.to_token_stream(), &mut idents);
+    idents.contains("grpcio")
+}
+
+// Whether `ty` is `::grpcio::<name>`.
+fn is_grpcio_type(ty: &Type, name: &str) -> bool {
+    match ty {
+        Type::Path(p) => {
+            let segments: Vec<_> = p
+                .path
+                .segments
+                .iter()
+                .map(|s| s.ident.to_string())
+                .collect();
+            segments == ["grpcio", name]
+        }
+        _ => false,
+    }
+}
+
+// Removes the `METHOD_*` constants, which describe the methods of services to
+// clients and servers, which nothing uses any more.
+fn remove_unused_methods(items: &mut Vec<Item>) {
+    let is_method = |item: &Item| match item {
+        Item::Const(c) if c.ident.to_string().starts_with("METHOD_") => Some(c.ident.to_string()),
+        _ => None,
+    };
+    let mut used = HashSet::new();
+    for item in items.iter().filter(|i| is_method(i).is_none()) {
+        add_idents(item.to_token_stream(), &mut used);
+    }
+    items.retain(|item| is_method(item).iter().all(|name| used.contains(name)));
+}
+
+fn add_idents(tokens: TokenStream, idents: &mut HashSet<String>) {
+    for token in tokens {
+        match token {
+            TokenTree::Ident(ident) => {
+                idents.insert(ident.to_string());
+            }
+            TokenTree::Group(group) => add_idents(group.stream(), idents),
+            _ => {}
+        }
+    }
+}
+
+// A service name without case or underscores, which is the same for the
+// CamelCase and snake_case names grpcio-compiler derives from it.
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| *c != '_')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ServiceFilter;
+
+    // The shape of grpcio-compiler's code for a service with one method.
+    fn service(name: &str, snake: &str) -> String {
+        format!(
+            "const METHOD_{upper}_GET: ::grpcio::Method<super::a::Req, super::a::Resp> = \
+             ::grpcio::Method {{ ty: ::grpcio::MethodType::Unary, name: \"/a.{name}/Get\" }};
+            #[derive(Clone)]
+            pub struct {name}Client {{ client: ::grpcio::Client }}
+            impl {name}Client {{
+                pub fn get(&self, req: &super::a::Req) -> ::grpcio::Result<super::a::Resp> {{
+                    self.client.unary_call(&METHOD_{upper}_GET, req, ::grpcio::CallOption::default())
+                }}
+            }}
+            pub trait {name} {{
+                fn get(&mut self, ctx: ::grpcio::RpcContext, req: super::a::Req, sink: ::grpcio::UnarySink<super::a::Resp>);
+            }}
+            pub fn create_{snake}<S: {name} + Send + Clone + 'static>(s: S) -> ::grpcio::Service {{
+                let mut instance = s;
+                ::grpcio::ServiceBuilder::new()
+                    .add_unary_handler(&METHOD_{upper}_GET, move |ctx, req, resp| instance.get(ctx, req, resp))
+                    .build()
+            }}
+            ",
+            name = name,
+            snake = snake,
+            upper = snake.to_uppercase()
+        )
+    }
+
+    fn fixup(filter: ServiceFilter, mut content: String) -> String {
+        let files = vec![(
+            PathBuf::from("a.rs"),
+            vec!["a.Greeter".to_owned(), "a.ImportSst".to_owned()],
+        )];
+        let fixup = Builder::new()
+            .grpc_services(filter)
+            .service_fixup(files.into_iter().collect())
+            .unwrap();
+        fixup(Path::new("a.rs"), &mut content);
+        content
+    }
+
+    fn trim(filter: ServiceFilter) -> String {
+        fixup(
+            filter,
+            service("Greeter", "greeter") + &service("ImportSst", "import_sst"),
+        )
+    }
+
+    #[test]
+    fn test_service_filter() {
+        let content = trim(ServiceFilter::matching(&["a.Import*"]));
+        assert!(!content.contains("Greeter"), "{}", content);
+        assert!(!content.contains("METHOD_GREETER_GET"), "{}", content);
+        assert!(
+            content.contains("pub struct ImportSstClient"),
+            "{}",
+            content
+        );
+        assert!(content.contains("pub fn create_import_sst"), "{}", content);
+
+        let content = trim(ServiceFilter::all().clients_only());
+        assert!(content.contains("impl GreeterClient"), "{}", content);
+        assert!(
+            content.contains("const METHOD_IMPORT_SST_GET"),
+            "{}",
+            content
+        );
+        assert!(!content.contains("pub trait"), "{}", content);
+        assert!(!content.contains("create_"), "{}", content);
+
+        let content = trim("servers:a.Greeter".parse().unwrap());
+        assert!(content.contains("pub trait Greeter"), "{}", content);
+        assert!(content.contains("const METHOD_GREETER_GET"), "{}", content);
+        assert!(!content.contains("Client"), "{}", content);
+        assert!(!content.contains("ImportSst"), "{}", content);
+    }
+    // With Prost, the services follow the messages, which are kept even when
+    // named like grpcio's code.
+    #[test]
+    fn test_prost_messages() {
+        let messages = "/// A message.
+            #[derive(Clone, PartialEq, ::prost::Message)]
+            pub struct GreeterClient {
+                #[prost(string, tag = \"1\")]
+                pub name: ::prost::alloc::string::String,
+            }
+            impl GreeterClient {
+                pub fn greeting(&self) -> String { format!(\"Hello, {}\", self.name) }
+            }
+            ";
+        let content = fixup(
+            "servers:a.Greeter".parse().unwrap(),
+            messages.to_owned()
+                + &service("Greeter", "greeter")
+                + &service("ImportSst", "import_sst"),
+        );
+        assert!(content.contains("pub name"), "{}", content);
+        assert!(content.contains("fn greeting"), "{}", content);
+        assert!(!content.contains("grpcio :: Client"), "{}", content);
+        assert!(content.contains("pub trait Greeter"), "{}", content);
+        assert!(!content.contains("ImportSst"), "{}", content);
+
+        // Files are only rewritten, losing comments, when something is removed.
+        let content = messages.to_owned() + &service("ImportSst", "import_sst");
+        assert_eq!(
+            fixup(ServiceFilter::matching(&["a.ImportSst"]), content.clone()),
+            content
+        );
+    }
+}
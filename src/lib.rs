@@ -14,19 +14,56 @@ mod protobuf_impl;
 #[cfg(feature = "prost-codec")]
 mod prost_impl;
 
+#[cfg(any(feature = "protobuf-codec", feature = "prost-codec"))]
+mod descriptor;
+
+#[cfg(any(feature = "protobuf-codec", feature = "prost-codec"))]
+mod dependency;
+
+#[cfg(any(feature = "protobuf-codec", feature = "prost-codec"))]
+mod sources;
+
+#[cfg(any(feature = "protobuf-codec", feature = "prost-codec"))]
+mod post_process;
+
+#[cfg(any(feature = "protobuf-codec", feature = "prost-codec"))]
+pub mod compat;
+
+#[cfg(any(feature = "protobuf-codec", feature = "prost-codec"))]
+pub mod lint;
+
+#[cfg(any(feature = "protobuf-codec", feature = "prost-codec"))]
+pub mod generator;
+
+#[cfg(any(feature = "grpcio-protobuf-codec", feature = "grpcio-prost-codec"))]
+mod grpc;
+
+mod diagnostics;
+
+#[cfg(feature = "config")]
+mod config;
+
+#[cfg(feature = "buf")]
+mod buf;
+
+#[cfg(all(test, any(feature = "protobuf-codec", feature = "prost-codec")))]
+mod fixtures;
+
 use bitflags::bitflags;
-use regex::Regex;
+#[doc(hidden)]
+pub use diagnostics::warn_on_stderr;
+use std::collections::BTreeMap;
 use std::env;
 use std::env::var;
 use std::fmt::Write as _;
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::str::from_utf8;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 // We use system protoc when its version matches,
 // otherwise use the protoc from bin which we bundle with the crate.
+#[cfg(any(not(feature = "pure-parser"), feature = "grpcio-prost-codec"))]
 fn get_protoc() -> String {
     // $PROTOC overrides everything; if it isn't a useful version then fail.
     if let Ok(s) = var("PROTOC") {
@@ -47,11 +84,21 @@ fn get_protoc() -> String {
         bin_path.display().to_string()
     }
 
-    #[cfg(not(windows))]
-    protobuf_src::protoc().display().to_string()
+    #[cfg(all(not(windows), feature = "protobuf-src"))]
+    {
+        protobuf_src::protoc().display().to_string()
+    }
+
+    #[cfg(all(not(windows), not(feature = "protobuf-src")))]
+    panic!("No usable `protoc` found; install protoc >= 3.1, set $PROTOC, or enable the `protobuf-src` feature")
 }
 
+#[cfg(any(not(feature = "pure-parser"), feature = "grpcio-prost-codec"))]
 fn check_protoc_version(protoc: &str) -> Result<String, ()> {
+    use regex::Regex;
+    use std::process::Command;
+    use std::str::from_utf8;
+
     let ver_re = Regex::new(r"([0-9]+)\.([0-9]+)(\.[0-9])?").unwrap();
     let output = Command::new(protoc).arg("--version").output();
     match output {
@@ -70,34 +117,126 @@ fn check_protoc_version(protoc: &str) -> Result<String, ()> {
     Err(())
 }
 
+#[derive(Clone)]
 pub struct Builder {
     files: Vec<String>,
+    generate_imports: bool,
+    prune_types: bool,
     includes: Vec<String>,
+    /// `(name, contents)` for `add_proto_source`.
+    proto_sources: Vec<(String, String)>,
+    /// Where the proto sources are written, once they are.
+    proto_source_dir: Option<PathBuf>,
     black_list: Vec<String>,
     out_dir: String,
     #[cfg(feature = "prost-codec")]
     wrapper_opts: GenOpt,
     package_name: Option<String>,
+    #[cfg(feature = "prost-codec")]
+    export_renames: Vec<(String, String)>,
+    #[cfg(feature = "prost-codec")]
+    module_aliases: Vec<(String, String)>,
+    #[cfg(feature = "prost-codec")]
+    stripped_module_prefixes: Vec<String>,
     #[cfg(feature = "grpcio-protobuf-codec")]
     re_export_services: bool,
+    #[cfg(any(feature = "grpcio-protobuf-codec", feature = "grpcio-prost-codec"))]
+    grpc_services: ServiceFilter,
+    #[cfg(feature = "protobuf-codec")]
+    module_layout: ModuleLayout,
+    #[cfg(feature = "protobuf-codec")]
+    unknown_enum_policy: Option<UnknownEnumPolicy>,
+    verbose: bool,
+    protoc_args: Vec<String>,
+    protoc_plugins: Vec<ProtocPlugin>,
+    #[cfg(any(feature = "protobuf-codec", feature = "prost-codec"))]
+    post_processors: Vec<post_process::PostProcessor>,
+    #[cfg(any(feature = "protobuf-codec", feature = "prost-codec"))]
+    generators: Vec<std::sync::Arc<dyn generator::Generator + Send + Sync>>,
+    descriptor_set_file: Option<String>,
+    compatibility_baseline: Option<String>,
+    compatibility_levels: Vec<(String, Level)>,
+    format: bool,
+    lint: bool,
+    lint_levels: Vec<(String, Level)>,
+    /// `(file, rule)` for `suppress_lint`.
+    lint_suppressions: Vec<(String, String)>,
+    export_includes: bool,
+    /// `(crate name, include)` for `include_from_dependency`.
+    dependencies: Vec<(String, String)>,
+    module_attributes: Vec<(String, String)>,
+    default_module_attributes: bool,
+    feature_gate_modules: bool,
+    #[cfg(feature = "config")]
+    config_file: Option<String>,
+}
+
+// An extra protoc plugin to run alongside code generation.
+#[cfg_attr(feature = "pure-parser", allow(dead_code))]
+#[derive(Clone)]
+struct ProtocPlugin {
+    name: String,
+    path: String,
+    out_dir: String,
+    params: String,
 }
 
 impl Builder {
     pub fn new() -> Builder {
         Builder {
             files: Vec::new(),
+            generate_imports: false,
+            prune_types: false,
             includes: vec!["include".to_owned(), "proto".to_owned()],
+            proto_sources: Vec::new(),
+            proto_source_dir: None,
             black_list: vec![
                 "protobuf".to_owned(),
                 "google".to_owned(),
                 "gogoproto".to_owned(),
             ],
-            out_dir: format!("{}/protos", var("OUT_DIR").expect("No OUT_DIR defined")),
+            // Outside of a build script, `out_dir` must be set explicitly.
+            out_dir: var("OUT_DIR")
+                .map(|d| format!("{}/protos", d))
+                .unwrap_or_default(),
             #[cfg(feature = "prost-codec")]
             wrapper_opts: GenOpt::all(),
             package_name: None,
+            #[cfg(feature = "prost-codec")]
+            export_renames: Vec::new(),
+            #[cfg(feature = "prost-codec")]
+            module_aliases: Vec::new(),
+            #[cfg(feature = "prost-codec")]
+            stripped_module_prefixes: Vec::new(),
             #[cfg(feature = "grpcio-protobuf-codec")]
             re_export_services: true,
+            #[cfg(any(feature = "grpcio-protobuf-codec", feature = "grpcio-prost-codec"))]
+            grpc_services: ServiceFilter::all(),
+            #[cfg(feature = "protobuf-codec")]
+            module_layout: ModuleLayout::Flat,
+            #[cfg(feature = "protobuf-codec")]
+            unknown_enum_policy: None,
+            verbose: false,
+            protoc_args: Vec::new(),
+            protoc_plugins: Vec::new(),
+            #[cfg(any(feature = "protobuf-codec", feature = "prost-codec"))]
+            post_processors: Vec::new(),
+            #[cfg(any(feature = "protobuf-codec", feature = "prost-codec"))]
+            generators: Vec::new(),
+            descriptor_set_file: None,
+            compatibility_baseline: None,
+            compatibility_levels: Vec::new(),
+            format: false,
+            lint: false,
+            lint_levels: Vec::new(),
+            lint_suppressions: Vec::new(),
+            export_includes: false,
+            dependencies: Vec::new(),
+            module_attributes: Vec::new(),
+            default_module_attributes: true,
+            feature_gate_modules: false,
+            #[cfg(feature = "config")]
+            config_file: None,
         }
     }
 
@@ -109,9 +248,73 @@ impl Builder {
 
     pub fn generate(&self) {
         assert!(!self.files.is_empty(), "No files specified for generation");
+        assert!(
+            !self.out_dir.is_empty(),
+            "No OUT_DIR defined and no out_dir specified"
+        );
+        if !self.proto_sources.is_empty() && self.proto_source_dir.is_none() {
+            match Builder::proto_source_root() {
+                Some(root) => self.with_proto_sources(&root).generate(),
+                None => {
+                    let scratch = ScratchDir::new();
+                    self.with_proto_sources(&scratch.0).generate();
+                }
+            }
+            return;
+        }
+        #[cfg(feature = "config")]
+        self.rerun_if_changed();
         self.prep_out_dir();
+        self.generate_all();
+        if self.export_includes {
+            self.write_exported_includes();
+        }
+    }
+
+    /// Generate the code like `generate`, but return it instead of writing it
+    /// to the output directory, e.g., for snapshot tests. Maps the path of
+    /// every generated `.rs` file (codegen, gRPC services, wrappers and
+    /// `mod.rs`), relative to the output directory, to its content.
+    ///
+    /// Neither `OUT_DIR` nor `out_dir` need to be set. `export_includes` is
+    /// ignored.
+    pub fn generate_to_memory(&self) -> BTreeMap<PathBuf, String> {
+        assert!(!self.files.is_empty(), "No files specified for generation");
+        // protoc and the codecs write files, so they work in a scratch
+        // directory which is removed again, even if generation panics.
+        let scratch = ScratchDir::new();
+        let out_dir = scratch.0.join("out");
+        let mut builder = if self.proto_sources.is_empty() {
+            self.clone()
+        } else {
+            self.with_proto_sources(&scratch.0.join("sources"))
+        };
+        builder.out_dir = out_dir.display().to_string();
+        builder.prep_out_dir();
+        builder.generate_all();
+        builder
+            .list_rs_files()
+            .map(|path| {
+                let content = fs::read_to_string(&path).expect("Couldn't read generated file");
+                (path.strip_prefix(&out_dir).unwrap().to_owned(), content)
+            })
+            .collect()
+    }
+
+    // Generates everything into `self.out_dir`, which must exist and be empty.
+    fn generate_all(&self) {
         self.generate_files();
-        self.generate_mod_file();
+        let included = self.run_generators();
+        self.post_process_files();
+        self.run_lints();
+        self.generate_mod_file(&included);
+        if self.feature_gate_modules {
+            self.write_features();
+        }
+        if self.format {
+            self.format_files();
+        }
+        self.write_headers();
     }
 
     /// This option is only used when generating Prost code. Otherwise, it is
@@ -135,6 +338,8 @@ impl Builder {
                 }
             })
             .collect();
+        // Directory order depends on the file system.
+        self.files.sort();
         self
     }
 
@@ -143,6 +348,24 @@ impl Builder {
         self
     }
 
+    /// Generate `roots` and every file they import, directly or indirectly,
+    /// except for files on the black list. Replaces the files to generate.
+    ///
+    /// Prost always generates imported files, so this is the same as `files`.
+    pub fn roots<T: ToString>(&mut self, roots: &[T]) -> &mut Self {
+        self.files(roots);
+        self.generate_imports = true;
+        self
+    }
+
+    /// Only generate the messages and enums of imported files which the files to
+    /// generate use, directly or indirectly. Imported files left without types
+    /// are not generated at all, nor are their services. Default is `false`.
+    pub fn prune_types(&mut self, prune_types: bool) -> &mut Self {
+        self.prune_types = prune_types;
+        self
+    }
+
     pub fn includes<T: ToString>(&mut self, includes: &[T]) -> &mut Self {
         self.includes = includes.iter().map(|t| t.to_string()).collect();
         self
@@ -175,13 +398,51 @@ impl Builder {
     /// If specified, a module with the given name will be generated which re-exports
     /// all generated items.
     ///
-    /// This is ignored by Prost, since Prost uses the package names of protocols
-    /// in any case.
+    /// With Prost, items from different packages may have the same name, which
+    /// is an error unless one of them is renamed with `rename_export`.
     pub fn package_name(&mut self, package_name: impl Into<String>) -> &mut Self {
         self.package_name = Some(package_name.into());
         self
     }
 
+    /// Re-export an item under a different name from the `package_name` module.
+    /// `item` is the proto package and the Rust name of the item, e.g.,
+    /// `eraftpb.Message`. Only used with Prost.
+    #[cfg(feature = "prost-codec")]
+    pub fn rename_export(
+        &mut self,
+        item: impl Into<String>,
+        new_name: impl Into<String>,
+    ) -> &mut Self {
+        self.export_renames.push((item.into(), new_name.into()));
+        self
+    }
+
+    /// Make the module of a proto package available at the top level of the
+    /// generated code under another name, e.g., with
+    /// `module_alias(".tikv.kvproto.v1", "kvproto")`, `kvproto::metapb` is
+    /// `tikv::kvproto::v1::metapb`. Only used with Prost.
+    ///
+    /// Top-level modules whose packages are all aliased are `#[doc(hidden)]`.
+    #[cfg(feature = "prost-codec")]
+    pub fn module_alias(
+        &mut self,
+        package: impl Into<String>,
+        alias: impl Into<String>,
+    ) -> &mut Self {
+        self.module_aliases.push((package.into(), alias.into()));
+        self
+    }
+
+    /// Make the modules of the packages under `prefix` available at the top level
+    /// of the generated code, e.g., with `strip_module_prefix(".tikv.kvproto.v1")`,
+    /// `metapb` is `tikv::kvproto::v1::metapb`. Only used with Prost.
+    #[cfg(feature = "prost-codec")]
+    pub fn strip_module_prefix(&mut self, prefix: impl Into<String>) -> &mut Self {
+        self.stripped_module_prefixes.push(prefix.into());
+        self
+    }
+
     /// Whether services defined in separate modules should be re-exported from
     /// their corresponding module. Default is `true`.
     #[cfg(feature = "grpcio-protobuf-codec")]
@@ -190,55 +451,454 @@ impl Builder {
         self
     }
 
-    fn generate_mod_file(&self) {
-        let mut f = File::create(format!("{}/mod.rs", self.out_dir)).unwrap();
+    /// Which gRPC services grpcio code is generated for, and whether for their
+    /// clients, servers or both, see `ServiceFilter`. Default is
+    /// `ServiceFilter::all()`.
+    ///
+    /// The code for services and sides which aren't selected is removed from
+    /// grpcio-compiler's output, which rewrites the files it is removed from
+    /// without their comments or formatting (see `format`).
+    #[cfg(any(feature = "grpcio-protobuf-codec", feature = "grpcio-prost-codec"))]
+    pub fn grpc_services(&mut self, filter: ServiceFilter) -> &mut Self {
+        self.grpc_services = filter;
+        self
+    }
+
+    /// How rust-protobuf modules are arranged, see `ModuleLayout`. Default is
+    /// `ModuleLayout::Flat`.
+    #[cfg(feature = "protobuf-codec")]
+    pub fn module_layout(&mut self, module_layout: ModuleLayout) -> &mut Self {
+        self.module_layout = module_layout;
+        self
+    }
+
+    /// How rust-protobuf code decodes enum values it doesn't know, see
+    /// `UnknownEnumPolicy`. By default, as in earlier versions, unknown values
+    /// of singular proto3 fields are an error (like `UnknownEnumPolicy::Error`)
+    /// and those of other fields are kept in the unknown fields (like
+    /// `UnknownEnumPolicy::PreserveInUnknownFields`).
+    #[cfg(feature = "protobuf-codec")]
+    pub fn unknown_enum_policy(&mut self, policy: UnknownEnumPolicy) -> &mut Self {
+        self.unknown_enum_policy = Some(policy);
+        self
+    }
 
-        let modules = self.list_rs_files().filter_map(|path| {
-            let name = path.file_stem().unwrap().to_str().unwrap();
+    /// Print progress information, such as the protoc command line, to the build
+    /// script's output. Default is `false`.
+    pub fn verbose(&mut self, verbose: bool) -> &mut Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Pass an extra argument to protoc, e.g., `--experimental_allow_proto3_optional`
+    /// or `--descriptor_set_in=deps.desc`.
+    ///
+    /// Not supported with the `pure-parser` feature. With `grpcio-prost-codec`,
+    /// the argument is not passed to the second protoc run made by grpcio-compiler.
+    pub fn protoc_arg(&mut self, arg: impl Into<String>) -> &mut Self {
+        self.protoc_args.push(arg.into());
+        self
+    }
+
+    /// Run an extra protoc plugin (e.g., a documentation or validation generator)
+    /// in the same protoc invocation as code generation.
+    ///
+    /// This passes `--plugin=protoc-gen-{name}={plugin_path}` and
+    /// `--{name}_out={params}:{out_dir}` to protoc. `params` may be empty.
+    /// Not supported with the `pure-parser` feature.
+    pub fn protoc_plugin(
+        &mut self,
+        name: impl Into<String>,
+        plugin_path: impl Into<String>,
+        out_dir: impl Into<String>,
+        params: impl Into<String>,
+    ) -> &mut Self {
+        self.protoc_plugins.push(ProtocPlugin {
+            name: name.into(),
+            path: plugin_path.into(),
+            out_dir: out_dir.into(),
+            params: params.into(),
+        });
+        self
+    }
+
+    /// Generate code from a descriptor set written by `protoc --include_imports -o`,
+    /// rather than compiling the proto files.
+    ///
+    /// The files to generate are then named as they appear in the descriptor set,
+    /// i.e., relative to an include directory.
+    pub fn descriptor_set_file(&mut self, path: impl Into<String>) -> &mut Self {
+        self.descriptor_set_file = Some(path.into());
+        self
+    }
+
+    /// Add an attribute (e.g., `#[cfg(feature = "raft")]` or a doc comment) to
+    /// generated modules. `pattern` is a module's path separated by `.`, e.g.,
+    /// `.tikv.kvproto` (with Prost, the proto package), in which `*` matches any
+    /// characters, including `.`, e.g., `.raft_*` or `.tikv.*`. `.` adds the
+    /// attribute to every top-level module.
+    ///
+    /// Attributes apply to everything nested in the module, including wrappers.
+    pub fn module_attribute(
+        &mut self,
+        pattern: impl Into<String>,
+        attribute: impl Into<String>,
+    ) -> &mut Self {
+        self.module_attributes
+            .push((pattern.into(), attribute.into()));
+        self
+    }
+
+    /// Whether to allow the lints generated code is known to trigger (e.g.,
+    /// `dead_code` and `clippy::all`) on every top-level module, so that the
+    /// generated code can be included without any attributes. Default is `true`.
+    pub fn default_module_attributes(&mut self, default_module_attributes: bool) -> &mut Self {
+        self.default_module_attributes = default_module_attributes;
+        self
+    }
+
+    /// Put each generated module behind a cargo feature, so that crates only
+    /// compile the modules they use. Features are named after module paths,
+    /// joined by `-`, e.g., `kvrpcpb` or (with Prost) `tikv-kvproto-metapb`.
+    ///
+    /// The features, with the features of imported modules as dependencies, are
+    /// written to `features.toml` in the output directory, ready to be copied
+    /// into `Cargo.toml`, and listed in a warning. Default is `false`.
+    pub fn feature_gate_modules(&mut self, feature_gate_modules: bool) -> &mut Self {
+        self.feature_gate_modules = feature_gate_modules;
+        self
+    }
+
+    /// Format the generated files with `rustfmt` (or `$RUSTFMT`). If it can't be
+    /// run, the files are left as generated. Default is `false`.
+    pub fn format(&mut self, format: bool) -> &mut Self {
+        self.format = format;
+        self
+    }
+
+    /// Set an option by name, as used by the `protoc-gen-rust-protobuf-build`
+    /// plugin and the `protobuf-build` command line tool.
+    ///
+    /// `include`, `black_list` and `protoc_arg` append to their lists; boolean
+    /// options take `true` or `false`; `wrapper_options` takes `GenOpt` flag
+    /// names separated by `|`; `module_layout` takes `flat`, `directory` or
+    /// `package`; `unknown_enum_policy` takes `error`,
+    /// `preserve_in_unknown_fields` or `default`; `grpc_services` takes a
+    /// `ServiceFilter` (see its `FromStr` implementation); `rename_export`
+    /// takes `package.Item=NewName`; `module_alias` takes `.package=alias`;
+    /// `compatibility_level` and `lint_level` take `RULE=level`, e.g.,
+    /// `FIELD_LABEL_CHANGED=warn`, and `suppress_lint` takes `file.proto=RULE`.
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<&mut Self, String> {
+        let parse_bool = |value: &str| {
+            value.parse::<bool>().map_err(|_| {
+                format!(
+                    "option `{}` expects `true` or `false`, got `{}`",
+                    name, value
+                )
+            })
+        };
+        match name {
+            "include" => self.append_include(value),
+            "black_list" => self.append_to_black_list(value),
+            "out_dir" => self.out_dir(value),
+            "package_name" => self.package_name(value),
+            "protoc_arg" => self.protoc_arg(value),
+            "verbose" => self.verbose(parse_bool(value)?),
+            "prune_types" => self.prune_types(parse_bool(value)?),
+            "default_module_attributes" => self.default_module_attributes(parse_bool(value)?),
+            "feature_gate_modules" => self.feature_gate_modules(parse_bool(value)?),
+            "export_includes" => self.export_includes(parse_bool(value)?),
+            "include_from_dependency" => self.include_from_dependency(value),
+            "format" => self.format(parse_bool(value)?),
+            "lint" => self.lint(parse_bool(value)?),
+            "lint_level" => match value.split_once('=') {
+                Some((rule, level)) if lint::RULES.contains(&rule) => {
+                    self.lint_level(rule, level.parse()?)
+                }
+                _ => {
+                    return Err(format!(
+                        "option `lint_level` expects `RULE=allow|warn|deny` with a rule from {:?}, got `{}`",
+                        lint::RULES,
+                        value
+                    ))
+                }
+            },
+            "suppress_lint" => match value.split_once('=') {
+                Some((file, rule)) if lint::RULES.contains(&rule) => self.suppress_lint(file, rule),
+                _ => {
+                    return Err(format!(
+                        "option `suppress_lint` expects `file.proto=RULE` with a rule from {:?}, got `{}`",
+                        lint::RULES,
+                        value
+                    ))
+                }
+            },
+            "check_compatibility_against" => self.check_compatibility_against(value),
+            "compatibility_level" => match value.split_once('=') {
+                Some((rule, level)) if compat::RULES.contains(&rule) => {
+                    self.compatibility_level(rule, level.parse()?)
+                }
+                _ => {
+                    return Err(format!(
+                        "option `compatibility_level` expects `RULE=allow|warn|deny` with a rule from {:?}, got `{}`",
+                        compat::RULES,
+                        value
+                    ))
+                }
+            },
+            #[cfg(feature = "grpcio-protobuf-codec")]
+            "re_export_services" => self.re_export_services(parse_bool(value)?),
+            #[cfg(any(feature = "grpcio-protobuf-codec", feature = "grpcio-prost-codec"))]
+            "grpc_services" => self.grpc_services(value.parse()?),
+            #[cfg(feature = "prost-codec")]
+            "wrapper_options" => self.wrapper_options(value.parse()?),
+            #[cfg(feature = "prost-codec")]
+            "rename_export" => match value.split_once('=') {
+                Some((item, new_name)) => self.rename_export(item, new_name),
+                None => {
+                    return Err(format!(
+                        "option `rename_export` expects `package.Item=NewName`, got `{}`",
+                        value
+                    ))
+                }
+            },
+            #[cfg(feature = "prost-codec")]
+            "module_alias" => match value.split_once('=') {
+                Some((package, alias)) => self.module_alias(package, alias),
+                None => {
+                    return Err(format!(
+                        "option `module_alias` expects `.package=alias`, got `{}`",
+                        value
+                    ))
+                }
+            },
+            #[cfg(feature = "prost-codec")]
+            "strip_module_prefix" => self.strip_module_prefix(value),
+            #[cfg(feature = "protobuf-codec")]
+            "module_layout" => self.module_layout(value.parse()?),
+            #[cfg(feature = "protobuf-codec")]
+            "unknown_enum_policy" => self.unknown_enum_policy(value.parse()?),
+            _ => return Err(format!("unknown option `{}`", name)),
+        };
+        Ok(self)
+    }
+
+    #[cfg(feature = "protobuf-codec")]
+    fn generate_mod_file(&self, included: &[generator::Included]) {
+        let mut exports = String::new();
+        let included: Vec<PathBuf> = included
+            .iter()
+            .map(|i| Path::new(&self.out_dir).join(&i.path))
+            .collect();
+        self.write_mod_file(Path::new(&self.out_dir), "", &mut exports, &included);
+
+        let dependency_exports = self.dependency_exports();
+        if !dependency_exports.is_empty() {
+            let mut f = fs::OpenOptions::new()
+                .append(true)
+                .open(format!("{}/mod.rs", self.out_dir))
+                .unwrap();
+            for export in dependency_exports {
+                if self.default_module_attributes {
+                    writeln!(f, "#[allow(unused_imports)]").unwrap();
+                }
+                writeln!(f, "{}", export).unwrap();
+            }
+        }
+
+        if !exports.is_empty() {
+            let mut f = fs::OpenOptions::new()
+                .append(true)
+                .open(format!("{}/mod.rs", self.out_dir))
+                .unwrap();
+            let package_name = self.package_name.as_ref().unwrap();
+            self.write_module_attributes(&mut f, &[package_name]);
+            writeln!(f, "pub mod {} {{ {} }}", package_name, exports).unwrap();
+        }
+    }
+
+    // rust-protobuf modules are files, which may be nested in directories (see
+    // `ModuleLayout`), each with its own `mod.rs`. `path` is the module path of
+    // `dir` relative to the output directory. Files of custom generators are
+    // `included` in other modules.
+    #[cfg(feature = "protobuf-codec")]
+    fn write_mod_file(&self, dir: &Path, path: &str, exports: &mut String, included: &[PathBuf]) {
+        let mut f = File::create(dir.join("mod.rs")).unwrap();
+
+        let mut entries: Vec<PathBuf> = fs::read_dir(dir)
+            .expect("Couldn't read directory")
+            .map(|e| e.expect("Couldn't list file").path())
+            .collect();
+        entries.sort();
+        for entry in entries {
+            let is_dir = entry.is_dir();
+            if !is_dir
+                && (entry.extension() != Some(std::ffi::OsStr::new("rs"))
+                    || included.contains(&entry))
+            {
+                continue;
+            }
+            let name = entry.file_stem().unwrap().to_str().unwrap();
             if name.starts_with("wrapper_")
                 || name == "mod"
                 || self.black_list.iter().any(|i| name.contains(i))
             {
-                return None;
+                continue;
             }
-            Some((name.replace('-', "_"), name.to_owned()))
-        });
-
-        let mut exports = String::new();
-        for (module, file_name) in modules {
-            if cfg!(feature = "protobuf-codec") {
-                if self.package_name.is_some() {
-                    writeln!(exports, "pub use super::{}::*;", module).unwrap();
-                } else {
-                    writeln!(f, "pub ").unwrap();
+            let module = name.replace('-', "_");
+            let module_path = format!("{}{}", path, module);
+            let parts: Vec<&str> = module_path.split("::").collect();
+            // Services are gated with their messages.
+            let gate = if self.feature_gate_modules && !is_dir {
+                let service_of = name
+                    .strip_suffix("_grpc")
+                    .filter(|n| entry.with_file_name(format!("{}.rs", n)).exists());
+                let mut feature_path = parts.clone();
+                if let Some(n) = service_of {
+                    *feature_path.last_mut().unwrap() = n;
                 }
-                writeln!(f, "mod {};", module).unwrap();
-                continue;
+                format!("#[cfg(feature = \"{}\")] ", module_feature(&feature_path))
+            } else {
+                String::new()
+            };
+            if is_dir {
+                self.write_mod_file(&entry, &format!("{}::", module_path), exports, included);
+            } else if self.package_name.is_some() {
+                writeln!(exports, "{}pub use super::{}::*;", gate, module_path).unwrap();
+            }
+            self.write_module_attributes(&mut f, &parts);
+            write!(f, "{}", gate).unwrap();
+            // With a package module, top-level modules are only reachable through it.
+            if self.package_name.is_none() || !path.is_empty() {
+                writeln!(f, "pub ").unwrap();
             }
+            writeln!(f, "mod {};", module).unwrap();
+        }
+    }
 
-            let mut level = 0;
-            for part in module.split('.') {
+    #[cfg(not(feature = "protobuf-codec"))]
+    fn generate_mod_file(&self, included: &[generator::Included]) {
+        let mut f = File::create(format!("{}/mod.rs", self.out_dir)).unwrap();
+        let modules = self.package_modules();
+        #[cfg(feature = "prost-codec")]
+        let (aliases, hidden) = self.module_aliases(&modules);
+
+        // Packages with a common prefix share the modules of the prefix, so write
+        // the modules as a tree, keeping the path of open modules.
+        let mut open: Vec<&str> = Vec::new();
+        for (module, file_name) in &modules {
+            let parts: Vec<&str> = module.split('.').collect();
+            let common = open.iter().zip(&parts).take_while(|(a, b)| a == b).count();
+            for _ in common..open.len() {
+                writeln!(f, "}}").unwrap();
+            }
+            open.truncate(common);
+            for part in &parts[common..] {
+                #[cfg(feature = "prost-codec")]
+                if open.is_empty() && hidden.iter().any(|h| h == part) {
+                    writeln!(f, "#[doc(hidden)]").unwrap();
+                }
+                open.push(part);
+                self.write_module_attributes(&mut f, &open);
                 writeln!(f, "pub mod {} {{", part).unwrap();
-                level += 1;
             }
-            writeln!(f, "include!(\"{}.rs\");", file_name,).unwrap();
+            // Gate the package's code rather than its module, which may contain
+            // the modules of other packages.
+            let gate = if self.feature_gate_modules {
+                format!("#[cfg(feature = \"{}\")] ", module_feature(&parts))
+            } else {
+                String::new()
+            };
+            writeln!(f, "{}include!(\"{}.rs\");", gate, file_name).unwrap();
             if Path::new(&format!("{}/wrapper_{}.rs", self.out_dir, file_name)).exists() {
-                writeln!(f, "include!(\"wrapper_{}.rs\");", file_name,).unwrap();
+                writeln!(f, "{}include!(\"wrapper_{}.rs\");", gate, file_name).unwrap();
+            }
+            for i in included.iter().filter(|i| i.module == parts) {
+                writeln!(f, "{}include!(\"{}\");", gate, i.path.display()).unwrap();
             }
-            writeln!(f, "{}", "}\n".repeat(level)).unwrap();
         }
+        writeln!(f, "{}", "}\n".repeat(open.len())).unwrap();
 
-        if !exports.is_empty() {
+        #[cfg(feature = "prost-codec")]
+        for (path, alias) in aliases {
+            if self.default_module_attributes {
+                writeln!(f, "#[allow(unused_imports)]").unwrap();
+            }
+            writeln!(f, "pub use self::{} as {};", path, alias).unwrap();
+        }
+
+        #[cfg(feature = "prost-codec")]
+        if let Some(package_name) = &self.package_name {
+            self.write_module_attributes(&mut f, &[package_name]);
             writeln!(
                 f,
-                "pub mod {} {{ {} }}",
-                self.package_name.as_ref().unwrap(),
-                exports
+                "pub mod {} {{\n{}}}",
+                package_name,
+                self.package_exports(&modules)
             )
             .unwrap();
         }
     }
 
+    // The generated packages as `(module, file_name)`, where `module` is the
+    // package with `.` separators.
+    #[cfg(not(feature = "protobuf-codec"))]
+    fn package_modules(&self) -> Vec<(String, String)> {
+        // Other files, e.g., from custom generators, are included in these.
+        let file_names: Vec<String> = self
+            .read_descriptor_set()
+            .get_file()
+            .iter()
+            .map(|f| prost_impl::package_file_name(f.get_package()))
+            .collect();
+        let mut modules: Vec<_> = self
+            .list_rs_files()
+            .filter_map(|path| {
+                let name = path.file_stem().unwrap().to_str().unwrap();
+                if !file_names.iter().any(|n| n == name)
+                    || self.black_list.iter().any(|i| name.contains(i))
+                {
+                    return None;
+                }
+                Some((name.replace('-', "_"), name.to_owned()))
+            })
+            .collect();
+        modules.sort_by(|(a, _), (b, _)| a.split('.').cmp(b.split('.')));
+        modules
+    }
+
+    // Writes the features for `feature_gate_modules` to `features.toml` and
+    // warns with them. Not on stdout, which belongs to cargo in a build script
+    // and to protoc in the plugin.
+    fn write_features(&self) {
+        let mut features = String::from("[features]\n");
+        for (feature, deps) in self.module_features() {
+            let deps: Vec<_> = deps.iter().map(|d| format!("\"{}\"", d)).collect();
+            writeln!(features, "{} = [{}]", feature, deps.join(", ")).unwrap();
+        }
+        // A cargo warning is one line.
+        diagnostics::warn("generated modules are behind these cargo features:");
+        features.lines().for_each(diagnostics::warn);
+        fs::write(format!("{}/features.toml", self.out_dir), features)
+            .expect("Could not write features.toml");
+    }
+
+    // Writes the attributes for the module at `path`, relative to the output
+    // directory.
+    fn write_module_attributes(&self, f: &mut File, path: &[&str]) {
+        if path.len() == 1 && self.default_module_attributes {
+            for attribute in DEFAULT_MODULE_ATTRIBUTES {
+                writeln!(f, "{}", attribute).unwrap();
+            }
+        }
+        let dotted = format!(".{}", path.join("."));
+        for (pattern, attribute) in &self.module_attributes {
+            if matches_pattern(pattern, &dotted) || (pattern == "." && path.len() == 1) {
+                writeln!(f, "{}", attribute).unwrap();
+            }
+        }
+    }
+
     fn prep_out_dir(&self) {
         if Path::new(&self.out_dir).exists() {
             fs::remove_dir_all(&self.out_dir).unwrap();
@@ -246,27 +906,333 @@ impl Builder {
         fs::create_dir_all(&self.out_dir).unwrap();
     }
 
-    // List all `.rs` files in `self.out_dir`.
+    // List all `.rs` files in `self.out_dir` and its subdirectories.
     fn list_rs_files(&self) -> impl Iterator<Item = PathBuf> {
-        fs::read_dir(&self.out_dir)
-            .expect("Couldn't read directory")
-            .filter_map(|e| {
+        let mut files = Vec::new();
+        let mut dirs = vec![PathBuf::from(&self.out_dir)];
+        while let Some(dir) = dirs.pop() {
+            for e in fs::read_dir(&dir).expect("Couldn't read directory") {
                 let path = e.expect("Couldn't list file").path();
-                if path.extension() == Some(std::ffi::OsStr::new("rs")) {
-                    Some(path)
-                } else {
-                    None
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path.extension() == Some(std::ffi::OsStr::new("rs")) {
+                    files.push(path);
                 }
+            }
+        }
+        // Directory order depends on the file system.
+        files.sort();
+        files.into_iter()
+    }
+
+    // Formats the generated files with rustfmt, if it is available.
+    fn format_files(&self) {
+        let rustfmt = var("RUSTFMT").unwrap_or_else(|_| "rustfmt".to_owned());
+        let result = std::process::Command::new(&rustfmt)
+            .arg("--edition")
+            .arg("2018")
+            .args(self.list_rs_files())
+            .output();
+        match result {
+            Ok(output) if output.status.success() => {}
+            Ok(output) => diagnostics::warn(format!(
+                "{} failed, generated code is not formatted: {}",
+                rustfmt,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+            Err(e) => diagnostics::warn(format!(
+                "couldn't run {}, generated code is not formatted: {}",
+                rustfmt, e
+            )),
+        }
+    }
+
+    // Prepends a header to every generated file, recording what generated it
+    // and a hash of all generated files. The hash is of the code rather than of
+    // the descriptor set, whose bytes depend on what parsed the protos.
+    fn write_headers(&self) {
+        let files: Vec<(PathBuf, String)> = self
+            .list_rs_files()
+            .map(|path| {
+                let content = fs::read_to_string(&path).unwrap();
+                (path, content)
             })
+            .collect();
+        let mut code = Vec::new();
+        for (path, content) in &files {
+            let relative = path.strip_prefix(&self.out_dir).unwrap();
+            code.extend(relative.to_string_lossy().replace('\\', "/").bytes());
+            code.push(0);
+            code.extend(content.bytes());
+            code.push(0);
+        }
+        let header = format!(
+            "// Generated by protobuf-build {} using {}, with code hash {:016x}.\n\n",
+            env!("CARGO_PKG_VERSION"),
+            self.parser_version(),
+            fnv1a(&code)
+        );
+        for (path, content) in files {
+            fs::write(&path, header.clone() + &content).expect("Could not write source file");
+        }
+    }
+
+    // What parsed the proto files, e.g., `libprotoc 3.21.12`.
+    fn parser_version(&self) -> String {
+        if self.descriptor_set_file.is_some() {
+            return "a descriptor set".to_owned();
+        }
+        #[cfg(feature = "pure-parser")]
+        {
+            "the pure parser".to_owned()
+        }
+        #[cfg(not(feature = "pure-parser"))]
+        {
+            let output = std::process::Command::new(get_protoc())
+                .arg("--version")
+                .output()
+                .expect("failed to execute protoc");
+            String::from_utf8_lossy(&output.stdout).trim().to_owned()
+        }
     }
 }
 
+// A fresh temporary directory, removed when dropped.
+struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    fn new() -> ScratchDir {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "protobuf-build-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        );
+        ScratchDir(env::temp_dir().join(name))
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// The 64-bit FNV-1a hash, which is stable across platforms and releases, unlike
+// `std`'s hashers.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+// The feature for a module with `feature_gate_modules`.
+fn module_feature(path: &[&str]) -> String {
+    path.join("-")
+}
+
+// Whether `name` matches `pattern`, in which `*` matches any characters.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            name.starts_with(prefix)
+                && (prefix.len()..=name.len())
+                    .any(|i| name.is_char_boundary(i) && matches_pattern(rest, &name[i..]))
+        }
+    }
+}
+
+// Lints which generated code is known to trigger. `unknown_lints` comes first
+// since not every compiler knows every lint.
+const DEFAULT_MODULE_ATTRIBUTES: &[&str] = &[
+    "#[allow(unknown_lints)]",
+    "#[allow(renamed_and_removed_lints)]",
+    "#[allow(dead_code)]",
+    "#[allow(unused_imports)]",
+    "#[allow(clippy::all)]",
+    "#[allow(bare_trait_objects)]",
+    "#[allow(unused_parens)]",
+    "#[allow(mismatched_lifetime_syntaxes)]",
+];
+
 impl Default for Builder {
     fn default() -> Builder {
         Builder::new()
     }
 }
 
+/// How the modules generated by rust-protobuf, one per proto file, are
+/// arranged. Prost always nests modules by proto package.
+#[cfg(feature = "protobuf-codec")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ModuleLayout {
+    /// All modules are in the output directory, named after their files. Files
+    /// with the same name in different directories are an error.
+    Flat,
+    /// Modules are nested by the directories of their files, e.g.,
+    /// `raft/service.proto` becomes `raft::service`.
+    Directory,
+    /// Modules are nested by proto package, e.g., `service.proto` in package
+    /// `raft.v1` becomes `raft::v1::service`.
+    Package,
+}
+
+#[cfg(feature = "protobuf-codec")]
+impl std::str::FromStr for ModuleLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ModuleLayout, String> {
+        match s {
+            "flat" => Ok(ModuleLayout::Flat),
+            "directory" => Ok(ModuleLayout::Directory),
+            "package" => Ok(ModuleLayout::Package),
+            _ => Err(format!("unknown module layout `{}`", s)),
+        }
+    }
+}
+
+/// How code generated by rust-protobuf decodes a value of an enum field which
+/// is not a value of the enum, e.g., one added in a newer version of the proto.
+///
+/// This applies to singular and repeated enum fields. Enums in `oneof`s
+/// (including proto3 `optional` fields) and maps are always an error.
+#[cfg(feature = "protobuf-codec")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnknownEnumPolicy {
+    /// Decoding the message fails.
+    Error,
+    /// The value is kept in the message's unknown fields, so it survives
+    /// re-encoding, and the field is left unchanged. This is what rust-protobuf
+    /// generates.
+    PreserveInUnknownFields,
+    /// The field gets the enum's default (first) value.
+    Default,
+}
+
+#[cfg(feature = "protobuf-codec")]
+impl std::str::FromStr for UnknownEnumPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<UnknownEnumPolicy, String> {
+        match s {
+            "error" => Ok(UnknownEnumPolicy::Error),
+            "preserve_in_unknown_fields" => Ok(UnknownEnumPolicy::PreserveInUnknownFields),
+            "default" => Ok(UnknownEnumPolicy::Default),
+            _ => Err(format!(
+                "unknown enum policy `{}`, expected error, preserve_in_unknown_fields or default",
+                s
+            )),
+        }
+    }
+}
+
+/// The gRPC services grpcio code is generated for, and which sides of them.
+///
+/// Services are picked by their fully qualified names, e.g., `tikvpb.Tikv`.
+/// Code for both clients and servers is generated unless `clients_only` or
+/// `servers_only` is used.
+#[cfg(any(feature = "grpcio-protobuf-codec", feature = "grpcio-prost-codec"))]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServiceFilter {
+    patterns: Vec<String>,
+    clients: bool,
+    servers: bool,
+}
+
+#[cfg(any(feature = "grpcio-protobuf-codec", feature = "grpcio-prost-codec"))]
+impl ServiceFilter {
+    /// All services.
+    pub fn all() -> ServiceFilter {
+        ServiceFilter::matching(&["*"])
+    }
+
+    /// The services whose names match any of `patterns`, in which `*` matches
+    /// any characters, e.g., `tikvpb.Tikv` or `debugpb.*`.
+    pub fn matching<T: ToString>(patterns: &[T]) -> ServiceFilter {
+        ServiceFilter {
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+            clients: true,
+            servers: true,
+        }
+    }
+
+    /// Only generate clients, e.g., for a crate which only calls the services.
+    pub fn clients_only(mut self) -> ServiceFilter {
+        self.clients = true;
+        self.servers = false;
+        self
+    }
+
+    /// Only generate servers, i.e., the service traits and `create_*` functions.
+    pub fn servers_only(mut self) -> ServiceFilter {
+        self.clients = false;
+        self.servers = true;
+        self
+    }
+
+    /// Whether code is generated for the service `name`.
+    pub fn selects(&self, name: &str) -> bool {
+        self.patterns.iter().any(|p| matches_pattern(p, name))
+    }
+}
+
+#[cfg(any(feature = "grpcio-protobuf-codec", feature = "grpcio-prost-codec"))]
+impl std::str::FromStr for ServiceFilter {
+    type Err = String;
+
+    /// Parses patterns separated by `,`, optionally preceded by `clients:` or
+    /// `servers:`, e.g., `clients:tikvpb.*,debugpb.Debug`.
+    fn from_str(s: &str) -> Result<ServiceFilter, String> {
+        let (side, patterns) = match s.split_once(':') {
+            Some((side, patterns)) => (Some(side), patterns),
+            None => (None, s),
+        };
+        let patterns: Vec<&str> = patterns.split(',').map(str::trim).collect();
+        if patterns.iter().any(|p| p.is_empty()) {
+            return Err(format!("empty service pattern in `{}`", s));
+        }
+        let filter = ServiceFilter::matching(&patterns);
+        match side {
+            None => Ok(filter),
+            Some("clients") => Ok(filter.clients_only()),
+            Some("servers") => Ok(filter.servers_only()),
+            Some(side) => Err(format!(
+                "unknown service side `{}`, expected clients or servers",
+                side
+            )),
+        }
+    }
+}
+
+/// How a violation of a rule (a compatibility rule from the `compat` module or
+/// a rule from the `lint` module) is reported.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Level {
+    /// Ignore it.
+    Allow,
+    /// Print a warning.
+    Warn,
+    /// Fail the build.
+    Deny,
+}
+
+impl std::str::FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Level, String> {
+        match s {
+            "allow" => Ok(Level::Allow),
+            "warn" => Ok(Level::Warn),
+            "deny" => Ok(Level::Deny),
+            _ => Err(format!(
+                "unknown level `{}`, expected allow, warn or deny",
+                s
+            )),
+        }
+    }
+}
+
 bitflags! {
     pub struct GenOpt: u32 {
         /// Generate implementation for trait `::protobuf::Message`.
@@ -299,3 +1265,143 @@ bitflags! {
          | Self::TAKE.bits;
     }
 }
+
+impl std::str::FromStr for GenOpt {
+    type Err = String;
+
+    /// Parses flag names separated by `|`, e.g., `"NO_MSG|NEW"`. `ALL` and
+    /// `NONE` are also accepted.
+    fn from_str(s: &str) -> Result<GenOpt, String> {
+        s.split('|')
+            .map(str::trim)
+            .try_fold(GenOpt::empty(), |opts, name| {
+                let flag = match name {
+                    "MESSAGE" => GenOpt::MESSAGE,
+                    "TRIVIAL_GET" => GenOpt::TRIVIAL_GET,
+                    "TRIVIAL_SET" => GenOpt::TRIVIAL_SET,
+                    "NEW" => GenOpt::NEW,
+                    "CLEAR" => GenOpt::CLEAR,
+                    "HAS" => GenOpt::HAS,
+                    "MUT" => GenOpt::MUT,
+                    "TAKE" => GenOpt::TAKE,
+                    "NO_MSG" => GenOpt::NO_MSG,
+                    "ACCESSOR" => GenOpt::ACCESSOR,
+                    "ALL" => GenOpt::all(),
+                    "NONE" => GenOpt::empty(),
+                    _ => return Err(format!("unknown wrapper option `{}`", name)),
+                };
+                Ok(opts | flag)
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_builder_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Builder>();
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    #[cfg(feature = "protobuf-codec")]
+    fn test_generate_to_memory() {
+        let files = crate::fixtures::nested().generate_to_memory();
+        let paths: Vec<_> = files.keys().map(|p| p.to_str().unwrap()).collect();
+        assert_eq!(paths, vec!["mod.rs", "nested.rs"]);
+        assert!(files[Path::new("mod.rs")].contains("mod nested;"));
+        assert!(files[Path::new("nested.rs")].starts_with("// Generated by protobuf-build"));
+    }
+
+    // Formatting is done before the headers are added, which only change with
+    // the code.
+    #[test]
+    #[cfg(any(feature = "protobuf-codec", feature = "prost-codec"))]
+    fn test_format_and_headers() {
+        let generate = |message: &str, format: bool| {
+            Builder::new()
+                .add_proto_source(
+                    "foo.proto",
+                    format!(
+                        "syntax = \"proto3\"; package foo; message {} {{ uint64 x = 1; }}",
+                        message
+                    ),
+                )
+                .files(&["foo.proto"])
+                .format(format)
+                .generate_to_memory()
+        };
+        let header = format!(
+            "// Generated by protobuf-build {} using {}, with code hash ",
+            env!("CARGO_PKG_VERSION"),
+            Builder::new().parser_version()
+        );
+        let hashes = |files: &BTreeMap<PathBuf, String>| -> Vec<String> {
+            files
+                .values()
+                .map(|content| {
+                    assert!(content.starts_with(&header), "{}", content);
+                    content[header.len()..header.len() + 18].to_owned()
+                })
+                .collect()
+        };
+
+        let formatted = generate("Bar", true);
+        let unformatted = generate("Bar", false);
+        assert_ne!(formatted, unformatted);
+        let hash = &hashes(&formatted)[0];
+        assert!(hash.ends_with(".\n"), "{}", hash);
+        assert!(hashes(&formatted).iter().all(|h| h == hash));
+        assert!(hashes(&unformatted).iter().all(|h| h != hash));
+        assert!(hashes(&generate("Baz", true)).iter().all(|h| h != hash));
+
+        let scratch = ScratchDir::new();
+        for (path, content) in &formatted {
+            let path = scratch.0.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        let status = std::process::Command::new("rustfmt")
+            .args(["--check", "--edition", "2018"])
+            .arg(scratch.0.join("mod.rs"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    #[cfg(feature = "protobuf-codec")]
+    fn test_module_attribute_patterns() {
+        let files = crate::fixtures::nested()
+            .default_module_attributes(false)
+            .module_attribute(".nest*", "#[cfg(feature = \"nested\")]")
+            .module_attribute(".*ed", "/// Nested events.")
+            .module_attribute(".other*", "#[cfg(feature = \"other\")]")
+            .generate_to_memory();
+        let mod_rs = &files[Path::new("mod.rs")];
+        assert!(
+            mod_rs.contains("#[cfg(feature = \"nested\")]\n/// Nested events.\npub "),
+            "{}",
+            mod_rs
+        );
+        assert!(!mod_rs.contains("other"), "{}", mod_rs);
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern(".tikv.raft", ".tikv.raft"));
+        assert!(!matches_pattern(".tikv.raft", ".tikv.raft_serverpb"));
+        assert!(matches_pattern(".tikv.*", ".tikv.raft.v1"));
+        assert!(matches_pattern("*.raft*", ".tikv.raft_serverpb"));
+        assert!(!matches_pattern(".tikv.*", ".tikv"));
+    }
+}
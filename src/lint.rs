@@ -0,0 +1,250 @@
+// Copyright 2019 PingCAP, Inc.
+
+//! Style rules for the proto files to generate, checked with `Builder::lint`.
+
+use protobuf::descriptor::{
+    DescriptorProto, EnumDescriptorProto, FieldDescriptorProto_Label, FileDescriptorProto,
+};
+
+use crate::compat::qualify;
+use crate::diagnostics::{report, Diagnostic, Severity};
+use crate::{Builder, Level};
+
+/// The zero value of an enum is not named `UNKNOWN` or `*_UNSPECIFIED` (or
+/// `*_UNKNOWN`), i.e., it is a meaningful value, which is what unset fields
+/// decode as.
+pub const ENUM_ZERO_VALUE_NAME: &str = "ENUM_ZERO_VALUE_NAME";
+/// A field name is not `snake_case`.
+pub const FIELD_NAME_SNAKE_CASE: &str = "FIELD_NAME_SNAKE_CASE";
+/// A field is `required`, which can never be removed compatibly.
+pub const REQUIRED_FIELD: &str = "REQUIRED_FIELD";
+/// A field name is also the name of an accessor generated for another field of
+/// the message, e.g., `has_foo` next to `foo`.
+pub const ACCESSOR_COLLISION: &str = "ACCESSOR_COLLISION";
+
+pub(crate) const RULES: &[&str] = &[
+    ENUM_ZERO_VALUE_NAME,
+    FIELD_NAME_SNAKE_CASE,
+    REQUIRED_FIELD,
+    ACCESSOR_COLLISION,
+];
+
+// The prefixes of the accessors generated for fields.
+const ACCESSOR_PREFIXES: &[&str] = &["has_", "clear_", "set_", "get_", "mut_", "take_"];
+
+/// A violation of a lint rule.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct Lint {
+    pub rule: &'static str,
+    pub message: String,
+}
+
+impl Builder {
+    /// Check the files to generate against the rules of the `lint` module.
+    /// Violations are warnings, unless changed with `lint_level`. Default is
+    /// `false`.
+    ///
+    /// Only the files given to `files` are checked, not the files they import,
+    /// even those which code is generated for (e.g., other packages with Prost).
+    pub fn lint(&mut self, lint: bool) -> &mut Self {
+        self.lint = lint;
+        self
+    }
+
+    /// Set the level of a lint rule, e.g., `lint_level(lint::REQUIRED_FIELD,
+    /// Level::Deny)`.
+    pub fn lint_level(&mut self, rule: &str, level: Level) -> &mut Self {
+        check_rule(rule);
+        self.lint_levels.push((rule.to_owned(), level));
+        self
+    }
+
+    /// Don't check `rule` for `file`, named relative to its include, e.g.,
+    /// `suppress_lint("raft_serverpb.proto", lint::ENUM_ZERO_VALUE_NAME)`.
+    pub fn suppress_lint(&mut self, file: impl Into<String>, rule: &str) -> &mut Self {
+        check_rule(rule);
+        self.lint_suppressions.push((file.into(), rule.to_owned()));
+        self
+    }
+
+    // Reports violations of the lint rules.
+    pub(crate) fn run_lints(&self) {
+        if !self.lint {
+            return;
+        }
+        let desc = self.read_descriptor_set();
+        let names = self.root_names();
+        let mut diagnostics = Vec::new();
+        for file in desc.get_file() {
+            if !names.iter().any(|n| n == file.get_name()) {
+                continue;
+            }
+            for l in lint_file(file) {
+                let suppressed = self
+                    .lint_suppressions
+                    .iter()
+                    .any(|(f, rule)| f == file.get_name() && rule == l.rule);
+                if suppressed {
+                    continue;
+                }
+                let severity = match self.lint_level_of(l.rule) {
+                    Level::Allow => continue,
+                    Level::Warn => Severity::Warning,
+                    Level::Deny => Severity::Error,
+                };
+                diagnostics.push(Diagnostic {
+                    severity,
+                    location: Some(file.get_name().to_owned()),
+                    message: format!("{}: {}", l.rule, l.message),
+                });
+            }
+        }
+        report("proto lints failed", &diagnostics);
+    }
+
+    fn lint_level_of(&self, rule: &str) -> Level {
+        self.lint_levels
+            .iter()
+            .rev()
+            .find(|(r, _)| r == rule)
+            .map_or(Level::Warn, |(_, level)| *level)
+    }
+}
+
+fn check_rule(rule: &str) {
+    assert!(
+        RULES.contains(&rule),
+        "unknown lint rule `{}`, expected one of {:?}",
+        rule,
+        RULES
+    );
+}
+
+/// The violations of all rules in `file`.
+pub(crate) fn lint_file(file: &FileDescriptorProto) -> Vec<Lint> {
+    let mut lints = Vec::new();
+    let prefix = file.get_package();
+    for message in file.get_message_type() {
+        lint_message(&qualify(prefix, message.get_name()), message, &mut lints);
+    }
+    for e in file.get_enum_type() {
+        lint_enum(&qualify(prefix, e.get_name()), e, &mut lints);
+    }
+    lints
+}
+
+fn lint_message(name: &str, message: &DescriptorProto, lints: &mut Vec<Lint>) {
+    // Map entries are generated, their fields are `key` and `value`.
+    if message.get_options().get_map_entry() {
+        return;
+    }
+    for field in message.get_field() {
+        let field_name = field.get_name();
+        if !is_snake_case(field_name) {
+            lints.push(Lint {
+                rule: FIELD_NAME_SNAKE_CASE,
+                message: format!("field `{}.{}` is not snake_case", name, field_name),
+            });
+        }
+        if field.get_label() == FieldDescriptorProto_Label::LABEL_REQUIRED {
+            lints.push(Lint {
+                rule: REQUIRED_FIELD,
+                message: format!("field `{}.{}` is required", name, field_name),
+            });
+        }
+        for other in message.get_field() {
+            let collides = ACCESSOR_PREFIXES
+                .iter()
+                .any(|p| field_name.strip_prefix(p) == Some(other.get_name()));
+            if collides {
+                lints.push(Lint {
+                    rule: ACCESSOR_COLLISION,
+                    message: format!(
+                        "field `{}.{}` has the name of an accessor of field `{}`",
+                        name,
+                        field_name,
+                        other.get_name()
+                    ),
+                });
+            }
+        }
+    }
+    for nested in message.get_nested_type() {
+        lint_message(&format!("{}.{}", name, nested.get_name()), nested, lints);
+    }
+    for e in message.get_enum_type() {
+        lint_enum(&format!("{}.{}", name, e.get_name()), e, lints);
+    }
+}
+
+fn lint_enum(name: &str, e: &EnumDescriptorProto, lints: &mut Vec<Lint>) {
+    let zero = match e.get_value().iter().find(|v| v.get_number() == 0) {
+        Some(zero) => zero.get_name(),
+        None => return,
+    };
+    if zero != "UNKNOWN" && !zero.ends_with("_UNKNOWN") && !zero.ends_with("_UNSPECIFIED") {
+        lints.push(Lint {
+            rule: ENUM_ZERO_VALUE_NAME,
+            message: format!(
+                "the zero value of enum `{}` is `{}`, not `UNKNOWN` or `*_UNSPECIFIED`",
+                name, zero
+            ),
+        });
+    }
+}
+
+fn is_snake_case(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_lowercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fixtures::{enum_type, field, file, message};
+    use protobuf::descriptor::FieldDescriptorProto_Type::TYPE_UINT64;
+
+    #[test]
+    fn test_lint_file() {
+        let mut key = field("key", 4, TYPE_UINT64);
+        key.set_label(FieldDescriptorProto_Label::LABEL_REQUIRED);
+        let mut message = message(
+            "Request",
+            vec![
+                field("region_id", 1, TYPE_UINT64),
+                field("has_region_id", 2, TYPE_UINT64),
+                field("regionEpoch", 3, TYPE_UINT64),
+                key,
+            ],
+        );
+        message
+            .mut_enum_type()
+            .push(enum_type("Kind", &["KIND_UNSPECIFIED"]));
+
+        let mut file = file("kvrpcpb.proto", "kvrpcpb", &[], vec![message]);
+        file.mut_enum_type().push(enum_type("State", &["UNKNOWN"]));
+        file.mut_enum_type().push(enum_type("Op", &["PUT"]));
+
+        let lints = lint_file(&file);
+        let rules: Vec<_> = lints.iter().map(|l| l.rule).collect();
+        assert_eq!(
+            rules,
+            vec![
+                ACCESSOR_COLLISION,
+                FIELD_NAME_SNAKE_CASE,
+                REQUIRED_FIELD,
+                ENUM_ZERO_VALUE_NAME
+            ]
+        );
+        assert_eq!(
+            lints[0].message,
+            "field `kvrpcpb.Request.has_region_id` has the name of an accessor of field `region_id`"
+        );
+        assert_eq!(
+            lints[3].message,
+            "the zero value of enum `kvrpcpb.Op` is `PUT`, not `UNKNOWN` or `*_UNSPECIFIED`"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright 2019 PingCAP, Inc.
+
+//! Hooks which rewrite generated files before `mod.rs` is written. The codecs'
+//! own fixups (see `Builder::fixups`) are hooks too, which run first.
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::Builder;
+
+/// A hook for `Builder::post_process`, called with the path of a generated
+/// file relative to the output directory and its content.
+pub(crate) type PostProcessor = Arc<dyn Fn(&Path, &mut String) + Send + Sync>;
+
+impl Builder {
+    /// Rewrite every generated file, i.e., code, gRPC services and wrappers,
+    /// with `hook`, which gets the path of the file relative to the output
+    /// directory (e.g., `kvrpcpb.rs`) and its content. Hooks run in the order
+    /// they are added, before `mod.rs` is written.
+    pub fn post_process(
+        &mut self,
+        hook: impl Fn(&Path, &mut String) + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.post_processors.push(Arc::new(hook));
+        self
+    }
+
+    /// Like `post_process`, but `hook` gets the parsed file. The file is printed
+    /// again without formatting, or comments other than doc comments, so this is
+    /// best combined with `format(true)`.
+    #[cfg(feature = "syn-post-process")]
+    pub fn post_process_syn(
+        &mut self,
+        hook: impl Fn(&Path, &mut syn::File) + Send + Sync + 'static,
+    ) -> &mut Self {
+        use quote::ToTokens;
+
+        self.post_process(move |path, content| {
+            let mut file = syn::parse_file(content)
+                .unwrap_or_else(|e| panic!("Couldn't parse {}: {}", path.display(), e));
+            hook(path, &mut file);
+            *content = file.into_token_stream().to_string();
+        })
+    }
+
+    // Runs the codec's fixups and then the `post_process` hooks over every
+    // generated file.
+    pub(crate) fn post_process_files(&self) {
+        let fixups = self.fixups();
+        if fixups.is_empty() && self.post_processors.is_empty() {
+            return;
+        }
+        for path in self.list_rs_files() {
+            let relative = path.strip_prefix(&self.out_dir).unwrap();
+            let original = fs::read_to_string(&path).expect("Couldn't read source file");
+            let mut content = original.clone();
+            for hook in fixups.iter().chain(&self.post_processors) {
+                hook(relative, &mut content);
+            }
+            if content != original {
+                fs::write(&path, content).expect("Could not write source file");
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "protobuf-codec"))]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_post_process() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = seen.clone();
+        let files = crate::fixtures::nested()
+            .post_process(move |path, content| {
+                hook_seen.lock().unwrap().push(path.to_owned());
+                content.push_str("// post-processed\n");
+            })
+            .generate_to_memory();
+        assert_eq!(*seen.lock().unwrap(), vec![Path::new("nested.rs")]);
+        let nested = &files[Path::new("nested.rs")];
+        assert!(nested.ends_with("// post-processed\n"));
+        // The codec's fixups ran too.
+        assert!(!nested.contains("read_proto3_enum_with_unknown_fields_into"));
+    }
+}
@@ -1,28 +1,508 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::PathBuf;
+
+use heck::{ToSnakeCase, ToUpperCamelCase};
+use protobuf::descriptor::{FileDescriptorProto, FileDescriptorSet};
+use syn::{Item, Visibility};
+
+use crate::diagnostics::{report, Diagnostic};
+use crate::generator::Included;
+use crate::post_process::PostProcessor;
 use crate::wrapper::WrapperGen;
-use crate::{get_protoc, Builder};
+use crate::{module_feature, Builder};
+
+#[cfg(all(feature = "grpcio-prost-codec", feature = "pure-parser"))]
+compile_error!("the `pure-parser` feature is not supported with `grpcio-prost-codec`, which always runs protoc");
 
 impl Builder {
     pub fn generate_files(&self) {
-        std::env::set_var("PROTOC", get_protoc());
+        let desc = self.descriptor_set();
 
         #[cfg(feature = "grpcio-prost-codec")]
         {
-            grpcio_compiler::prost_codegen::compile_protos(
-                &self.files,
-                &self.includes,
-                &self.out_dir,
-            )
-            .unwrap();
+            assert!(
+                self.dependencies.is_empty(),
+                "`include_from_dependency` is not supported with `grpcio-prost-codec`"
+            );
+            // grpcio-compiler can't take a descriptor set, so it runs protoc again,
+            // with the same include order so that files get the same names.
+            let _ = desc;
+            let includes = self.include_dirs();
+            let files: Vec<_> = self
+                .input_files(&includes)
+                .into_iter()
+                .map(|f| f.path)
+                .collect();
+            std::env::set_var("PROTOC", crate::get_protoc());
+            grpcio_compiler::prost_codegen::compile_protos(&files, &includes, &self.out_dir)
+                .unwrap();
         }
         #[cfg(not(feature = "grpcio-prost-codec"))]
         {
-            prost_build::Config::new()
+            let mut desc = desc;
+            let mut config = prost_build::Config::new();
+            for (package, path) in self.extern_packages(&mut desc) {
+                config.extern_path(package, path);
+            }
+            config
                 .out_dir(&self.out_dir)
-                .compile_protos(&self.files, &self.includes)
+                .compile_fds(to_prost_descriptor_set(&desc))
                 .unwrap();
         }
 
         self.list_rs_files()
             .for_each(|path| WrapperGen::new(path, self.wrapper_opts).write());
     }
+
+    /// The fixups for generated files, which run before the `post_process`
+    /// hooks. Prost's output needs none, only grpcio's.
+    pub(crate) fn fixups(&self) -> Vec<PostProcessor> {
+        self.grpc_service_fixup().into_iter().collect()
+    }
+
+    /// grpcio-compiler appends the services of each package to Prost's file.
+    #[cfg(feature = "grpcio-prost-codec")]
+    fn grpc_service_fixup(&self) -> Option<PostProcessor> {
+        use crate::compat::qualify;
+
+        let desc = self.read_descriptor_set();
+        let mut files: HashMap<PathBuf, Vec<String>> = HashMap::new();
+        for file in desc.get_file() {
+            if file.get_service().is_empty() {
+                continue;
+            }
+            let path = format!("{}.rs", package_file_name(file.get_package()));
+            files.entry(PathBuf::from(path)).or_default().extend(
+                file.get_service()
+                    .iter()
+                    .map(|s| qualify(file.get_package(), s.get_name())),
+            );
+        }
+        self.service_fixup(files)
+    }
+
+    #[cfg(not(feature = "grpcio-prost-codec"))]
+    fn grpc_service_fixup(&self) -> Option<PostProcessor> {
+        None
+    }
+
+    /// The module of each file with generated code, for custom generators.
+    pub(crate) fn generated_modules(
+        &self,
+        desc: &FileDescriptorSet,
+    ) -> HashMap<String, Vec<String>> {
+        let modules = self.package_modules();
+        let dependencies: Vec<&str> = self
+            .dependency_files(desc)
+            .into_iter()
+            .map(|(f, _)| f.get_name())
+            .collect();
+        desc.get_file()
+            .iter()
+            .filter(|f| !dependencies.contains(&f.get_name()))
+            .filter_map(|f| {
+                let file_name = package_file_name(f.get_package());
+                let (module, _) = modules.iter().find(|(_, n)| *n == file_name)?;
+                let module = module.split('.').map(str::to_owned).collect();
+                Some((f.get_name().to_owned(), module))
+            })
+            .collect()
+    }
+
+    /// The module with the types of `file`, which may be a dependency, given
+    /// the `generated_modules`.
+    pub(crate) fn type_module(
+        &self,
+        desc: &FileDescriptorSet,
+        file: &FileDescriptorProto,
+        modules: &HashMap<String, Vec<String>>,
+    ) -> Option<Vec<String>> {
+        if let Some(module) = modules.get(file.get_name()) {
+            return Some(module.clone());
+        }
+        // Absolute paths start with an empty segment.
+        if file.get_package() == "google.protobuf" {
+            return Some(vec![String::new(), "prost_types".to_owned()]);
+        }
+        let (_, crate_name) = self
+            .dependency_files(desc)
+            .into_iter()
+            .find(|(f, _)| f.get_name() == file.get_name())?;
+        let mut module = vec![String::new(), crate_name.to_owned()];
+        module.extend(file.get_package().split('.').map(to_snake));
+        Some(module)
+    }
+
+    /// The directory, relative to the output directory, for the files of
+    /// custom generators. They are included in `mod.rs`, like wrappers.
+    pub(crate) fn included_dir(&self, _module: &[String]) -> PathBuf {
+        PathBuf::new()
+    }
+
+    pub(crate) fn include_generated(&self, _included: &[Included]) {}
+
+    /// Removes files from dependencies from `desc`, returning `extern_path`s
+    /// for their packages, e.g., `(".metapb", "::kvproto::metapb")`.
+    #[cfg(not(feature = "grpcio-prost-codec"))]
+    fn extern_packages(&self, desc: &mut FileDescriptorSet) -> Vec<(String, String)> {
+        let external: Vec<(String, String, String)> = self
+            .dependency_files(desc)
+            .into_iter()
+            .map(|(f, crate_name)| {
+                (
+                    f.get_name().to_owned(),
+                    f.get_package().to_owned(),
+                    crate_name.to_owned(),
+                )
+            })
+            .collect();
+        desc.mut_file()
+            .retain(|f| !external.iter().any(|(name, _, _)| name == f.get_name()));
+
+        let mut paths = Vec::new();
+        let mut diagnostics = Vec::new();
+        for (name, package, crate_name) in external {
+            // Prost generates a package as one module, which can't be split
+            // between crates.
+            if package.is_empty() {
+                diagnostics.push(Diagnostic::error(format!(
+                    "{} from `{}` has no package, so its types can't be referred to",
+                    name, crate_name
+                )));
+            } else if let Some(local) = desc.get_file().iter().find(|f| f.get_package() == package)
+            {
+                diagnostics.push(Diagnostic::error(format!(
+                    "package `{}` is in both {} from `{}` and {}",
+                    package,
+                    name,
+                    crate_name,
+                    local.get_name()
+                )));
+            }
+            let path = (
+                format!(".{}", package),
+                format!("::{}::{}", crate_name, package.replace('.', "::")),
+            );
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        report("failed to use protos from dependencies", &diagnostics);
+        paths
+    }
+
+    /// The features for `feature_gate_modules`: one per package, depending on the
+    /// features of the packages it imports.
+    pub(crate) fn module_features(&self) -> BTreeMap<String, BTreeSet<String>> {
+        let modules = self.package_modules();
+        let desc = self.read_descriptor_set();
+        let packages: HashMap<&str, &str> = desc
+            .get_file()
+            .iter()
+            .map(|f| (f.get_name(), f.get_package()))
+            .collect();
+        let feature = |package: &str| {
+            let module = package.replace('-', "_");
+            if modules.iter().any(|(m, _)| *m == module) {
+                let parts: Vec<&str> = module.split('.').collect();
+                Some(module_feature(&parts))
+            } else {
+                None
+            }
+        };
+
+        let mut features: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for file in desc.get_file() {
+            let feature_name = match feature(file.get_package()) {
+                Some(f) => f,
+                None => continue,
+            };
+            let deps = features.entry(feature_name.clone()).or_default();
+            for dep in file.get_dependency() {
+                if let Some(dep) = packages.get(&**dep).and_then(|p| feature(p)) {
+                    if dep != feature_name {
+                        deps.insert(dep);
+                    }
+                }
+            }
+        }
+        features
+    }
+
+    /// `pub use` items for the `package_name` module, which re-exports all items
+    /// of the given modules (as `(module, file_name)`, where `module` is the
+    /// proto package).
+    pub(crate) fn package_exports(&self, modules: &[(String, String)]) -> String {
+        let sources: Vec<_> = modules
+            .iter()
+            .map(|(module, file_name)| {
+                let path = format!("{}/{}.rs", self.out_dir, file_name);
+                let source = fs::read_to_string(&path)
+                    .unwrap_or_else(|e| panic!("Couldn't read {}: {}", path, e));
+                (module.clone(), source)
+            })
+            .collect();
+        export_items(&sources, &self.export_renames, self.feature_gate_modules).unwrap_or_else(
+            |diagnostics| {
+                report("failed to generate the package module", &diagnostics);
+                unreachable!()
+            },
+        )
+    }
+}
+
+impl Builder {
+    /// The aliases for `module_alias` and `strip_module_prefix`, as `(path, alias)`,
+    /// and the top-level modules which are hidden because all their packages are
+    /// aliased. `modules` are `(module, file_name)`, as for `package_exports`.
+    pub(crate) fn module_aliases(
+        &self,
+        modules: &[(String, String)],
+    ) -> (Vec<(String, String)>, Vec<String>) {
+        let packages: Vec<Vec<String>> = modules
+            .iter()
+            .map(|(m, _)| m.split('.').map(str::to_owned).collect())
+            .collect();
+        let mut aliases = Vec::new();
+        let mut diagnostics = Vec::new();
+        for (package, alias) in &self.module_aliases {
+            let target = module_parts(package);
+            if !packages.iter().any(|p| p.starts_with(&target)) {
+                diagnostics.push(Diagnostic::error(format!(
+                    "`module_alias`: no package `{}`",
+                    package
+                )));
+            }
+            aliases.push((target, alias.clone()));
+        }
+        for prefix in &self.stripped_module_prefixes {
+            let prefix_parts = module_parts(prefix);
+            let mut children: Vec<&str> = packages
+                .iter()
+                .filter(|p| p.len() > prefix_parts.len() && p.starts_with(&prefix_parts))
+                .map(|p| &*p[prefix_parts.len()])
+                .collect();
+            children.dedup();
+            if children.is_empty() {
+                diagnostics.push(Diagnostic::error(format!(
+                    "`strip_module_prefix`: no packages under `{}`",
+                    prefix
+                )));
+            }
+            for child in children {
+                let mut target = prefix_parts.clone();
+                target.push(child.to_owned());
+                aliases.push((target, child.to_owned()));
+            }
+        }
+
+        let mut names: Vec<&str> = packages.iter().map(|p| &*p[0]).collect();
+        for (target, alias) in &aliases {
+            if names.contains(&&**alias) {
+                diagnostics.push(Diagnostic::error(format!(
+                    "alias `{}` for `{}` is already the name of a module",
+                    alias,
+                    target.join(".")
+                )));
+            }
+            names.push(alias);
+        }
+        report("failed to alias modules", &diagnostics);
+
+        let mut hidden: Vec<String> = Vec::new();
+        for p in &packages {
+            if !hidden.contains(&p[0])
+                && packages
+                    .iter()
+                    .filter(|q| q[0] == p[0])
+                    .all(|q| aliases.iter().any(|(target, _)| q.starts_with(target)))
+            {
+                hidden.push(p[0].clone());
+            }
+        }
+        let aliases = aliases
+            .into_iter()
+            .map(|(target, alias)| (target.join("::"), alias))
+            .collect();
+        (aliases, hidden)
+    }
+}
+
+/// The name (without `.rs`) of the file Prost generates for `package`.
+pub(crate) fn package_file_name(package: &str) -> String {
+    if package.is_empty() {
+        return "_".to_owned();
+    }
+    let parts: Vec<String> = package.split('.').map(to_snake).collect();
+    parts.join(".")
+}
+
+/// Prost's name for a module or field, e.g., for the module of the types
+/// nested in a message.
+pub(crate) fn to_snake(s: &str) -> String {
+    let mut ident = s.to_snake_case();
+    match &*ident {
+        "as" | "break" | "const" | "continue" | "else" | "enum" | "false" | "fn" | "for" | "if"
+        | "impl" | "in" | "let" | "loop" | "match" | "mod" | "move" | "mut" | "pub" | "ref"
+        | "return" | "static" | "struct" | "trait" | "true" | "type" | "unsafe" | "use"
+        | "where" | "while" | "dyn" | "abstract" | "become" | "box" | "do" | "final" | "macro"
+        | "override" | "priv" | "typeof" | "unsized" | "virtual" | "yield" | "async" | "await"
+        | "try" => ident.insert_str(0, "r#"),
+        // These can't be raw identifiers.
+        "self" | "super" | "extern" | "crate" => ident += "_",
+        _ => {}
+    }
+    ident
+}
+
+/// Prost's name for a message or enum.
+pub(crate) fn to_upper_camel(s: &str) -> String {
+    let mut ident = s.to_upper_camel_case();
+    if ident == "Self" {
+        ident += "_";
+    }
+    ident
+}
+
+// Module names for a package, which may start with `.`.
+fn module_parts(package: &str) -> Vec<String> {
+    package
+        .trim_start_matches('.')
+        .split('.')
+        .map(|p| p.replace('-', "_"))
+        .collect()
+}
+
+// Re-exports the public items of each `(package, source)`, failing if two
+// packages export the same name.
+fn export_items(
+    sources: &[(String, String)],
+    renames: &[(String, String)],
+    feature_gate: bool,
+) -> Result<String, Vec<Diagnostic>> {
+    // Exported name to the package and original name of the item.
+    let mut exports: BTreeMap<String, (&str, String)> = BTreeMap::new();
+    let mut diagnostics = Vec::new();
+    for (package, source) in sources {
+        let file = syn::parse_file(source).expect("Couldn't parse generated code");
+        for item in &file.items {
+            let ident = match item {
+                Item::Struct(i) if is_pub(&i.vis) => &i.ident,
+                Item::Enum(i) if is_pub(&i.vis) => &i.ident,
+                Item::Mod(i) if is_pub(&i.vis) => &i.ident,
+                Item::Type(i) if is_pub(&i.vis) => &i.ident,
+                Item::Const(i) if is_pub(&i.vis) => &i.ident,
+                Item::Static(i) if is_pub(&i.vis) => &i.ident,
+                Item::Fn(i) if is_pub(&i.vis) => &i.sig.ident,
+                Item::Trait(i) if is_pub(&i.vis) => &i.ident,
+                _ => continue,
+            };
+            let name = ident.to_string();
+            let qualified = format!("{}.{}", package, name);
+            let export = renames
+                .iter()
+                .find(|(item, _)| *item == qualified)
+                .map(|(_, new_name)| new_name.clone())
+                .unwrap_or_else(|| name.clone());
+            if let Some((other, other_name)) = exports.get(&export) {
+                diagnostics.push(Diagnostic::error(format!(
+                    "`{}` is exported by both `{}.{}` and `{}`, rename one with `Builder::rename_export`",
+                    export, other, other_name, qualified
+                )));
+                continue;
+            }
+            exports.insert(export, (package, name));
+        }
+    }
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+
+    let mut result = String::new();
+    for (export, (package, name)) in exports {
+        if feature_gate {
+            let parts: Vec<&str> = package.split('.').collect();
+            write!(result, "#[cfg(feature = \"{}\")] ", module_feature(&parts)).unwrap();
+        }
+        let path = package.replace('.', "::");
+        if export == name {
+            writeln!(result, "pub use super::{}::{};", path, name).unwrap();
+        } else {
+            writeln!(result, "pub use super::{}::{} as {};", path, name, export).unwrap();
+        }
+    }
+    Ok(result)
+}
+
+fn is_pub(vis: &Visibility) -> bool {
+    matches!(vis, Visibility::Public(_))
+}
+
+// Prost uses its own descriptor types, so round-trip through the wire format.
+#[cfg(not(feature = "grpcio-prost-codec"))]
+fn to_prost_descriptor_set(
+    desc: &protobuf::descriptor::FileDescriptorSet,
+) -> prost_types::FileDescriptorSet {
+    use prost::Message as _;
+    use protobuf::Message as _;
+
+    prost_types::FileDescriptorSet::decode(&*desc.write_to_bytes().unwrap())
+        .expect("Could not convert descriptor set")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_module_aliases() {
+        let modules: Vec<_> = [
+            "google.protobuf",
+            "tikv.kvproto.v1.metapb",
+            "tikv.kvproto.v1.pdpb",
+        ]
+        .iter()
+        .map(|m| (m.to_string(), m.to_string()))
+        .collect();
+        let mut builder = Builder::new();
+        builder.strip_module_prefix(".tikv.kvproto.v1");
+        builder.module_alias(".google.protobuf", "pb");
+        let (aliases, hidden) = builder.module_aliases(&modules);
+        assert_eq!(
+            aliases,
+            vec![
+                ("google::protobuf".to_owned(), "pb".to_owned()),
+                ("tikv::kvproto::v1::metapb".to_owned(), "metapb".to_owned()),
+                ("tikv::kvproto::v1::pdpb".to_owned(), "pdpb".to_owned()),
+            ]
+        );
+        assert_eq!(hidden, vec!["google".to_owned(), "tikv".to_owned()]);
+    }
+
+    #[test]
+    fn test_export_items() {
+        let sources = vec![
+            (
+                "eraftpb".to_owned(),
+                "pub struct Message {} pub enum MessageType {} pub mod message {} struct Private;"
+                    .to_owned(),
+            ),
+            ("raft.v1".to_owned(), "pub struct Message {}".to_owned()),
+        ];
+
+        let err = export_items(&sources, &[], false).unwrap_err();
+        assert_eq!(err.len(), 1);
+        assert!(err[0].message.contains("`raft.v1.Message`"), "{}", err[0]);
+
+        let renames = vec![("raft.v1.Message".to_owned(), "RaftMessage".to_owned())];
+        assert_eq!(
+            export_items(&sources, &renames, false).unwrap(),
+            "pub use super::eraftpb::Message;\n\
+             pub use super::eraftpb::MessageType;\n\
+             pub use super::raft::v1::Message as RaftMessage;\n\
+             pub use super::eraftpb::message;\n"
+        );
+    }
 }
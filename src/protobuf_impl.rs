@@ -1,146 +1,665 @@
 // Copyright 2019 PingCAP, Inc.
 
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
-use std::process::Command;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use protobuf::Message;
+use protobuf::compiler_plugin::GenResult;
+use protobuf::descriptor::{
+    DescriptorProto, FieldDescriptorProto_Type, FileDescriptorProto, FileDescriptorSet,
+};
 use regex::Regex;
 
-use crate::get_protoc;
-use crate::Builder;
+use crate::diagnostics::{report, Diagnostic};
+use crate::generator::Included;
+use crate::post_process::PostProcessor;
+use crate::{module_feature, Builder, ModuleLayout, UnknownEnumPolicy};
 
 impl Builder {
     pub fn generate_files(&self) {
-        let mut cmd = Command::new(get_protoc());
-        let desc_file = format!("{}/mod.desc", self.out_dir);
-        for i in &self.includes {
-            cmd.arg(format!("-I{}", i));
-        }
-        cmd.arg("--include_imports")
-            .arg("--include_source_info")
-            .arg("-o")
-            .arg(&desc_file);
-        for f in &self.files {
-            cmd.arg(f);
+        let desc = self.descriptor_set();
+        let files_to_generate = self.file_names(&desc);
+        let files: HashMap<&str, &FileDescriptorProto> =
+            desc.get_file().iter().map(|f| (f.get_name(), f)).collect();
+        // References to files from dependencies are rewritten too, they are
+        // re-exported by `dependency_exports`.
+        let mut laid_out = files_to_generate.clone();
+        laid_out.extend(
+            self.dependency_files(&desc)
+                .into_iter()
+                .map(|(f, _)| f.get_name().to_owned()),
+        );
+        let modules = self.module_paths(&files, &laid_out);
+
+        // Generate one file at a time to know which proto each result is for.
+        for file in &files_to_generate {
+            let mut results = protobuf_codegen::gen(
+                desc.get_file(),
+                std::slice::from_ref(file),
+                &protobuf_codegen::Customize::default(),
+            );
+            results.extend(self.generate_grpcio(desc.get_file(), std::slice::from_ref(file)));
+            for result in results {
+                self.write_result(files[&**file], result, &files, &modules);
+            }
         }
-        println!("executing {:?}", cmd);
-        match cmd.status() {
-            Ok(e) if e.success() => {}
-            e => panic!("failed to generate descriptor set files: {:?}", e),
+    }
+
+    /// The fixups for generated files, which run before the `post_process`
+    /// hooks.
+    pub(crate) fn fixups(&self) -> Vec<PostProcessor> {
+        let mut fixups: Vec<PostProcessor> = Vec::new();
+        fixups.extend(self.unknown_enum_fixup());
+        fixups.extend(self.import_grpcio());
+        fixups.extend(self.grpc_service_fixup());
+        fixups
+    }
+
+    /// Rewrites how enum fields are read for `unknown_enum_policy`. It fails if
+    /// rust-protobuf doesn't read enums as expected, rather than leaving some
+    /// of them with its own policy.
+    fn unknown_enum_fixup(&self) -> Option<PostProcessor> {
+        let policy = self.unknown_enum_policy;
+        if policy == Some(UnknownEnumPolicy::PreserveInUnknownFields) {
+            return None;
         }
+        // The number of enum fields read in the file at each path.
+        let desc = self.read_descriptor_set();
+        let expected: HashMap<PathBuf, usize> = self
+            .file_names(&desc)
+            .iter()
+            .filter_map(|name| {
+                let file = desc.get_file().iter().find(|f| f.get_name() == name)?;
+                let mut path: PathBuf = self.module_path(file).iter().collect();
+                path.set_extension("rs");
+                Some((path, file.get_message_type().iter().map(enum_reads).sum()))
+            })
+            .collect();
+        let regex = Regex::new(
+            r"::protobuf::rt::read_(proto2|proto3|repeated)_enum_with_unknown_fields_into\((\w+), (\w+), &mut ([^,]+), \d+, &mut self\.unknown_fields\)\?",
+        )
+        .unwrap();
 
-        let desc_bytes = std::fs::read(&desc_file).unwrap();
-        let mut desc = protobuf::descriptor::FileDescriptorSet::new();
-        desc.merge_from_bytes(&desc_bytes).unwrap();
-        desc.check_initialized().unwrap();
-
-        let mut files_to_generate = Vec::new();
-        'outer: for file in &self.files {
-            for include in &self.includes {
-                if let Ok(truncated) = Path::new(file).strip_prefix(include) {
-                    files_to_generate.push(format!("{}", truncated.display()));
-                    continue 'outer;
+        Some(Arc::new(move |path: &Path, content: &mut String| {
+            let calls = content.matches("_enum_with_unknown_fields_into(").count();
+            let mut found = 0;
+            let text = regex.replace_all(content, |caps: &regex::Captures| {
+                found += 1;
+                match policy {
+                    Some(policy) => read_enum(policy, &caps[1], &caps[2], &caps[3], &caps[4]),
+                    // Without a policy, only singular proto3 fields are
+                    // rewritten, like earlier versions did.
+                    None if &caps[1] == "proto3" => read_enum(
+                        UnknownEnumPolicy::Error,
+                        &caps[1],
+                        &caps[2],
+                        &caps[3],
+                        &caps[4],
+                    ),
+                    None => caps[0].to_owned(),
                 }
+            });
+            let found_all = expected.get(path).iter().all(|n| **n == found) && calls == found;
+            assert!(
+                found_all,
+                "{}: couldn't find how all enum fields are read, the code generated by rust-protobuf \
+                 changed; only `UnknownEnumPolicy::PreserveInUnknownFields` works with it",
+                path.display()
+            );
+            if let Cow::Owned(text) = text {
+                *content = text;
             }
+        }))
+    }
 
-            panic!(
-                "file {:?} is not found in includes {:?}",
-                file, self.includes
-            );
+    /// The module of each file to generate, for custom generators.
+    pub(crate) fn generated_modules(
+        &self,
+        desc: &FileDescriptorSet,
+    ) -> HashMap<String, Vec<String>> {
+        self.file_names(desc)
+            .into_iter()
+            .filter_map(|name| {
+                let file = desc.get_file().iter().find(|f| f.get_name() == name)?;
+                Some((name, self.module_path(file)))
+            })
+            .collect()
+    }
+
+    /// The module with the types of `file`, which may be a dependency, given
+    /// the `generated_modules`.
+    pub(crate) fn type_module(
+        &self,
+        desc: &FileDescriptorSet,
+        file: &FileDescriptorProto,
+        modules: &HashMap<String, Vec<String>>,
+    ) -> Option<Vec<String>> {
+        if let Some(module) = modules.get(file.get_name()) {
+            return Some(module.clone());
+        }
+        if file.get_package() == "google.protobuf" {
+            return Some(vec![
+                String::new(),
+                "protobuf".to_owned(),
+                "well_known_types".to_owned(),
+            ]);
         }
+        // Modules of dependencies are re-exported by `dependency_exports`.
+        self.dependency_files(desc)
+            .into_iter()
+            .find(|(f, _)| f.get_name() == file.get_name())
+            .map(|(f, _)| self.module_path(f))
+    }
 
-        protobuf_codegen::gen_and_write(
-            desc.get_file(),
-            &files_to_generate,
-            Path::new(&self.out_dir),
-            &protobuf_codegen::Customize::default(),
-        )
-        .unwrap();
-        self.generate_grpcio(desc.get_file(), &files_to_generate);
-        self.import_grpcio();
-        self.replace_read_unknown_fields();
-    }
-
-    /// Convert protobuf files to use the old way of reading protobuf enums.
-    // FIXME: Remove this once stepancheg/rust-protobuf#233 is resolved.
-    fn replace_read_unknown_fields(&self) {
-        let regex =
-            Regex::new(r"::protobuf::rt::read_proto3_enum_with_unknown_fields_into\(([^,]+), ([^,]+), &mut ([^,]+), [^\)]+\)\?").unwrap();
-        self.list_rs_files().for_each(|path| {
-            let mut text = String::new();
-            let mut f = File::open(&path).unwrap();
-            f.read_to_string(&mut text)
-                .expect("Couldn't read source file");
-
-            // FIXME Rustfmt bug in string literals
-            #[rustfmt::skip]
-            let text = {
-                regex.replace_all(
-                    &text,
-                    "if $1 == ::protobuf::wire_format::WireTypeVarint {\
-                        $3 = $2.read_enum()?;\
-                    } else {\
-                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));\
-                    }",
-                )
-            };
-            let mut out = File::create(&path).unwrap();
-            out.write_all(text.as_bytes())
-                .expect("Could not write source file");
-        });
+    /// The directory, relative to the output directory, for the files of
+    /// custom generators which are included in `module`.
+    pub(crate) fn included_dir(&self, module: &[String]) -> PathBuf {
+        module[..module.len() - 1].iter().collect()
+    }
+
+    /// Includes the files of custom generators in the files of their modules.
+    pub(crate) fn include_generated(&self, included: &[Included]) {
+        for i in included {
+            let mut path = PathBuf::from(&self.out_dir);
+            path.extend(&i.module);
+            path.set_extension("rs");
+            let mut content = fs::read_to_string(&path).expect("Couldn't read source file");
+            content.push_str(&format!(
+                "include!(\"{}\");\n",
+                i.path.file_name().unwrap().to_str().unwrap()
+            ));
+            fs::write(&path, content).expect("Could not write source file");
+        }
     }
 
+    /// The features for `feature_gate_modules`: one per generated file, depending
+    /// on the features of the generated files it imports.
+    pub(crate) fn module_features(&self) -> BTreeMap<String, BTreeSet<String>> {
+        let desc = self.read_descriptor_set();
+        let files_to_generate = self.file_names(&desc);
+        let files: HashMap<&str, &FileDescriptorProto> =
+            desc.get_file().iter().map(|f| (f.get_name(), f)).collect();
+        let modules = self.module_paths(&files, &files_to_generate);
+        let feature = |name: &str| {
+            let parts: Vec<&str> = modules[name].iter().map(|p| &**p).collect();
+            module_feature(&parts)
+        };
+
+        let mut features = BTreeMap::new();
+        for name in &files_to_generate {
+            let deps = files[&**name]
+                .get_dependency()
+                .iter()
+                .filter(|d| modules.contains_key(&***d))
+                .map(|d| feature(d))
+                .collect();
+            features.insert(feature(name), deps);
+        }
+        features
+    }
+
+    // Maps the files to generate to their module paths, relative to the output
+    // directory.
+    fn module_paths<'a>(
+        &self,
+        files: &HashMap<&str, &FileDescriptorProto>,
+        files_to_generate: &'a [String],
+    ) -> HashMap<&'a str, Vec<String>> {
+        let mut modules: HashMap<&str, Vec<String>> = HashMap::new();
+        let mut diagnostics = Vec::new();
+        for name in files_to_generate {
+            let module = self.module_path(files[&**name]);
+            if let Some((other, m)) = modules
+                .iter()
+                .find(|(_, m)| m.starts_with(&module) || module.starts_with(m))
+            {
+                let shorter = if m.len() < module.len() { m } else { &module };
+                diagnostics.push(Diagnostic::error(format!(
+                    "{} and {} both need module `{}`, use `Builder::module_layout` to keep them apart",
+                    other,
+                    name,
+                    shorter.join("::")
+                )));
+            }
+            modules.insert(name, module);
+        }
+        report("failed to lay out generated modules", &diagnostics);
+        modules
+    }
+
+    /// `pub use` items for the top-level modules of files from dependencies,
+    /// which generated code refers to as `super::module`.
+    pub(crate) fn dependency_exports(&self) -> Vec<String> {
+        let desc = self.read_descriptor_set();
+        let mut exports: Vec<String> = self
+            .dependency_files(&desc)
+            .into_iter()
+            .filter(|(f, _)| !self.black_list.iter().any(|b| f.get_name().contains(b)))
+            .map(|(f, crate_name)| format!("pub use ::{}::{};", crate_name, self.module_path(f)[0]))
+            .collect();
+        exports.sort();
+        exports.dedup();
+        exports
+    }
+
+    /// Write a file generated for `file` to its place in the module layout. Code
+    /// generated by rust-protobuf and grpcio refers to other files as
+    /// `super::file::Type`, which is rewritten to the path of that file's module.
+    fn write_result(
+        &self,
+        file: &FileDescriptorProto,
+        result: GenResult,
+        files: &HashMap<&str, &FileDescriptorProto>,
+        modules: &HashMap<&str, Vec<String>>,
+    ) {
+        let module = &modules[file.get_name()];
+        let depth = module.len();
+        let mut path = PathBuf::from(&self.out_dir);
+        path.extend(&module[..depth - 1]);
+        path.push(&result.name);
+
+        let mut content = String::from_utf8(result.content).unwrap();
+        if self.module_layout != ModuleLayout::Flat {
+            let reference =
+                Regex::new(r"\b((?:super::)+)([A-Za-z_][A-Za-z0-9_]*)::([A-Za-z_][A-Za-z0-9_]*)")
+                    .unwrap();
+            let deps = dependencies(file, files);
+            content = reference
+                .replace_all(&content, |caps: &regex::Captures| {
+                    let supers = caps[1].len() / "super::".len();
+                    let target = deps.iter().find(|dep| {
+                        modules.get(dep.get_name()).and_then(|m| m.last())
+                            == Some(&caps[2].to_owned())
+                            && defines(dep, &caps[3])
+                    });
+                    match target {
+                        Some(dep) => format!(
+                            "{}{}::{}",
+                            "super::".repeat(depth + supers - 1),
+                            modules[dep.get_name()].join("::"),
+                            &caps[3]
+                        ),
+                        None => caps[0].to_owned(),
+                    }
+                })
+                .into_owned();
+        }
+
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).expect("Could not write source file");
+    }
+
+    /// The path of the module of `file`, relative to the output directory.
+    pub(crate) fn module_path(&self, file: &FileDescriptorProto) -> Vec<String> {
+        let name = file.get_name();
+        let mut path: Vec<String> = match self.module_layout {
+            ModuleLayout::Flat => Vec::new(),
+            ModuleLayout::Directory => {
+                let dirs: Vec<&str> = name.split('/').collect();
+                dirs[..dirs.len() - 1]
+                    .iter()
+                    .map(|d| rust_mod_name(d))
+                    .collect()
+            }
+            ModuleLayout::Package => file
+                .get_package()
+                .split('.')
+                .filter(|p| !p.is_empty())
+                .map(rust_mod_name)
+                .collect(),
+        };
+        let stem = name.rsplit('/').next().unwrap().trim_end_matches(".proto");
+        path.push(rust_mod_name(stem));
+        path
+    }
+
+    /// Re-exports the gRPC services of each file from its module, if any of
+    /// them are selected by `grpc_services`.
     #[cfg(feature = "grpcio-protobuf-codec")]
-    fn import_grpcio(&self) {
-        use std::collections::BTreeMap;
-        use std::fs::OpenOptions;
+    fn import_grpcio(&self) -> Option<PostProcessor> {
+        use std::collections::HashSet;
+        use std::fmt::Write as _;
 
         if !self.re_export_services {
-            return;
+            return None;
         }
 
-        // TODO should be behind an option
-        let paths: BTreeMap<_, _> = self
-            .list_rs_files()
-            .map(|path| (path.file_stem().unwrap().to_str().unwrap().to_owned(), path))
+        let with_services: HashSet<PathBuf> = self
+            .grpc_files()
+            .into_keys()
+            .map(|path| {
+                let name = path.file_stem().unwrap().to_str().unwrap();
+                path.with_file_name(format!("{}.rs", name.strip_suffix("_grpc").unwrap()))
+            })
             .collect();
-        for (name, path) in &paths {
-            if name.starts_with("wrapper_")
-                || *name == "mod"
-                || name.ends_with("_grpc")
-                || !paths.contains_key(&*format!("{}_grpc", name))
-            {
-                continue;
+        Some(Arc::new(move |path: &Path, content: &mut String| {
+            if with_services.contains(path) {
+                let name = path.file_stem().unwrap().to_str().unwrap();
+                writeln!(content, "pub use super::{}_grpc::*;", name).unwrap();
             }
+        }))
+    }
 
-            let mut out = OpenOptions::new()
-                .append(true)
-                .open(&path)
-                .expect("Couldn't open source file");
-            writeln!(out, "pub use super::{}_grpc::*;", name).expect("Could not write source file");
-        }
+    #[cfg(not(feature = "grpcio-protobuf-codec"))]
+    fn import_grpcio(&self) -> Option<PostProcessor> {
+        None
+    }
+
+    #[cfg(feature = "grpcio-protobuf-codec")]
+    fn grpc_service_fixup(&self) -> Option<PostProcessor> {
+        self.service_fixup(self.grpc_files())
     }
 
     #[cfg(not(feature = "grpcio-protobuf-codec"))]
-    fn import_grpcio(&self) {}
+    fn grpc_service_fixup(&self) -> Option<PostProcessor> {
+        None
+    }
+
+    /// The `_grpc.rs` file of each file to generate which has any services
+    /// selected by `grpc_services`, with all its services.
+    #[cfg(feature = "grpcio-protobuf-codec")]
+    fn grpc_files(&self) -> HashMap<PathBuf, Vec<String>> {
+        use crate::compat::qualify;
+
+        let desc = self.read_descriptor_set();
+        self.file_names(&desc)
+            .iter()
+            .filter_map(|name| {
+                let file = desc.get_file().iter().find(|f| f.get_name() == name)?;
+                let services: Vec<String> = file
+                    .get_service()
+                    .iter()
+                    .map(|s| qualify(file.get_package(), s.get_name()))
+                    .collect();
+                if !services.iter().any(|s| self.grpc_services.selects(s)) {
+                    return None;
+                }
+                let module = self.module_path(file);
+                let mut path: PathBuf = module[..module.len() - 1].iter().collect();
+                path.push(format!("{}_grpc.rs", module[module.len() - 1]));
+                Some((path, services))
+            })
+            .collect()
+    }
 
     #[cfg(feature = "grpcio-protobuf-codec")]
     fn generate_grpcio(
         &self,
-        desc: &[protobuf::descriptor::FileDescriptorProto],
+        desc: &[FileDescriptorProto],
         files_to_generate: &[String],
-    ) {
-        let output_dir = std::path::Path::new(&self.out_dir);
-        let results = grpcio_compiler::codegen::gen(desc, &files_to_generate);
-        for res in results {
-            let out_file = output_dir.join(&res.name);
-            let mut f = std::fs::File::create(&out_file).unwrap();
-            f.write_all(&res.content).unwrap();
-        }
+    ) -> Vec<GenResult> {
+        use crate::compat::qualify;
+
+        // Files without any selected services are not generated.
+        let desc: Vec<FileDescriptorProto> = desc
+            .iter()
+            .map(|file| {
+                let mut file = file.clone();
+                let package = file.get_package().to_owned();
+                file.mut_service()
+                    .retain(|s| self.grpc_services.selects(&qualify(&package, s.get_name())));
+                file
+            })
+            .collect();
+        grpcio_compiler::codegen::gen(&desc, files_to_generate)
     }
 
     #[cfg(not(feature = "grpcio-protobuf-codec"))]
-    fn generate_grpcio(&self, _: &[protobuf::descriptor::FileDescriptorProto], _: &[String]) {}
+    fn generate_grpcio(&self, _: &[FileDescriptorProto], _: &[String]) -> Vec<GenResult> {
+        Vec::new()
+    }
+}
+
+// The number of enum fields rust-protobuf reads with
+// `rt::read_*_enum_with_unknown_fields_into` in `message` and its nested
+// messages: all except those in `oneof`s and maps.
+fn enum_reads(message: &DescriptorProto) -> usize {
+    if message.get_options().get_map_entry() {
+        return 0;
+    }
+    let fields = message
+        .get_field()
+        .iter()
+        .filter(|f| {
+            f.get_field_type() == FieldDescriptorProto_Type::TYPE_ENUM && !f.has_oneof_index()
+        })
+        .count();
+    fields
+        + message
+            .get_nested_type()
+            .iter()
+            .map(enum_reads)
+            .sum::<usize>()
+}
+
+// Code which reads an enum field like
+// `rt::read_{kind}_enum_with_unknown_fields_into(wire_type, is, &mut field, ..)`,
+// but with `policy`.
+fn read_enum(
+    policy: UnknownEnumPolicy,
+    kind: &str,
+    wire_type: &str,
+    is: &str,
+    field: &str,
+) -> String {
+    let value = match policy {
+        UnknownEnumPolicy::Default => format!(
+            "::protobuf::ProtobufEnum::from_i32({}.read_int32()?).unwrap_or_default()",
+            is
+        ),
+        _ => format!("{}.read_enum()?", is),
+    };
+    let unexpected = format!(
+        "return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type({}))",
+        wire_type
+    );
+    match kind {
+        "proto3" => format!(
+            "if {} == ::protobuf::wire_format::WireTypeVarint {{{} = {};}} else {{{};}}",
+            wire_type, field, value, unexpected
+        ),
+        "proto2" => format!(
+            "if {} == ::protobuf::wire_format::WireTypeVarint {{{} = ::std::option::Option::Some({});}} else {{{};}}",
+            wire_type, field, value, unexpected
+        ),
+        _ if policy == UnknownEnumPolicy::Error => format!(
+            "::protobuf::rt::read_repeated_enum_into({}, {}, &mut {})?",
+            wire_type, is, field
+        ),
+        // Repeated, either packed or not.
+        _ => format!(
+            "match {wire_type} {{\
+                ::protobuf::wire_format::WireTypeLengthDelimited => {{\
+                    let len = {is}.read_raw_varint64()?;\
+                    let old_limit = {is}.push_limit(len)?;\
+                    while !{is}.eof()? {{{field}.push({value});}}\
+                    {is}.pop_limit(old_limit);\
+                }}\
+                ::protobuf::wire_format::WireTypeVarint => {field}.push({value}),\
+                _ => {unexpected},\
+            }}",
+            wire_type = wire_type,
+            is = is,
+            field = field,
+            value = value,
+            unexpected = unexpected
+        ),
+    }
+}
+
+// The files `file` can refer to: itself and everything it imports.
+fn dependencies<'a>(
+    file: &'a FileDescriptorProto,
+    files: &HashMap<&str, &'a FileDescriptorProto>,
+) -> Vec<&'a FileDescriptorProto> {
+    let mut result = vec![file];
+    let mut i = 0;
+    while i < result.len() {
+        for dep in result[i].get_dependency() {
+            if let Some(dep) = files.get(&**dep) {
+                if !result.iter().any(|f| f.get_name() == dep.get_name()) {
+                    result.push(dep);
+                }
+            }
+        }
+        i += 1;
+    }
+    result
+}
+
+// Whether `ident` is the Rust name of a type defined in `file`. Nested types are
+// named `Outer_Inner`.
+fn defines(file: &FileDescriptorProto, ident: &str) -> bool {
+    file.get_message_type()
+        .iter()
+        .map(|m| m.get_name())
+        .chain(file.get_enum_type().iter().map(|e| e.get_name()))
+        .any(|name| ident == name || ident.starts_with(&format!("{}_", name)))
+}
+
+// The same as rust-protobuf's names for file modules.
+fn rust_mod_name(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "as", "async", "await", "break", "crate", "dyn", "else", "enum", "extern", "false", "fn",
+        "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref",
+        "return", "static", "self", "Self", "struct", "super", "true", "trait", "type", "unsafe",
+        "use", "while", "continue", "box", "const", "where", "virtual", "proc", "alignof",
+        "become", "offsetof", "priv", "pure", "sizeof", "typeof", "unsized", "yield", "do",
+        "abstract", "final", "override", "macro",
+    ];
+    let name: String = name
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if c.is_ascii_alphabetic() || c == '_' || (i > 0 && c.is_ascii_digit()) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if KEYWORDS.contains(&&*name) {
+        format!("{}_pb", name)
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Two files named `service.proto`, one of which imports the other.
+    fn services(layout: ModuleLayout) -> BTreeMap<PathBuf, String> {
+        Builder::new()
+            .add_proto_source(
+                "raft/service.proto",
+                "syntax = \"proto3\";\npackage tikv.raft;\nmessage Message { uint64 term = 1; }",
+            )
+            .add_proto_source(
+                "debug/service.proto",
+                "syntax = \"proto3\";\npackage debug;\nimport \"raft/service.proto\";\n\
+                 message Request { tikv.raft.Message msg = 1; }",
+            )
+            .files(&["raft/service.proto", "debug/service.proto"])
+            .module_layout(layout)
+            .generate_to_memory()
+    }
+
+    fn paths(files: &BTreeMap<PathBuf, String>) -> Vec<&str> {
+        files.keys().map(|p| p.to_str().unwrap()).collect()
+    }
+
+    fn enums(builder: &mut Builder) -> String {
+        let mut files = builder
+            .includes(&["tests/proto"])
+            .files(&["tests/proto/enums.proto"])
+            .generate_to_memory();
+        files.remove(Path::new("enums.rs")).unwrap()
+    }
+
+    // Without a policy, only singular proto3 fields are rewritten, to the code
+    // earlier versions generated.
+    #[test]
+    fn test_unknown_enum_reads() {
+        let content = enums(&mut Builder::new());
+        assert!(
+            content.contains(
+                "if wire_type == ::protobuf::wire_format::WireTypeVarint {self.color = is.read_enum()?;} \
+                 else {return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));}"
+            ),
+            "{}",
+            content
+        );
+        assert!(
+            content.contains(
+                "::protobuf::rt::read_repeated_enum_with_unknown_fields_into(\
+                 wire_type, is, &mut self.colors, 2, &mut self.unknown_fields)?"
+            ),
+            "{}",
+            content
+        );
+
+        let content = enums(Builder::new().unknown_enum_policy(UnknownEnumPolicy::Error));
+        assert!(
+            !content.contains("_enum_with_unknown_fields_into("),
+            "{}",
+            content
+        );
+        assert!(content
+            .contains("::protobuf::rt::read_repeated_enum_into(wire_type, is, &mut self.colors)?"));
+    }
+
+    #[test]
+    fn test_directory_layout() {
+        let files = services(ModuleLayout::Directory);
+        assert_eq!(
+            paths(&files),
+            vec![
+                "debug/mod.rs",
+                "debug/service.rs",
+                "mod.rs",
+                "raft/mod.rs",
+                "raft/service.rs"
+            ]
+        );
+        let root = &files[Path::new("mod.rs")];
+        assert!(root.contains("mod debug;") && root.contains("mod raft;"));
+        assert!(files[Path::new("raft/mod.rs")].contains("mod service;"));
+        // `super::service::Message` from rust-protobuf is rewritten.
+        let debug = &files[Path::new("debug/service.rs")];
+        assert!(debug.contains("super::super::raft::service::Message"));
+        assert!(!debug.contains(" super::service::"));
+    }
+
+    #[test]
+    fn test_package_layout() {
+        let files = services(ModuleLayout::Package);
+        assert_eq!(
+            paths(&files),
+            vec![
+                "debug/mod.rs",
+                "debug/service.rs",
+                "mod.rs",
+                "tikv/mod.rs",
+                "tikv/raft/mod.rs",
+                "tikv/raft/service.rs"
+            ]
+        );
+        assert!(files[Path::new("mod.rs")].contains("mod tikv;"));
+        assert!(files[Path::new("tikv/mod.rs")].contains("mod raft;"));
+        assert!(files[Path::new("tikv/raft/mod.rs")].contains("mod service;"));
+        let debug = &files[Path::new("debug/service.rs")];
+        assert!(debug.contains("super::super::tikv::raft::service::Message"));
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "raft/service.proto and debug/service.proto both need module `service`"
+    )]
+    fn test_flat_layout_conflict() {
+        services(ModuleLayout::Flat);
+    }
 }
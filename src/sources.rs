@@ -0,0 +1,116 @@
+// Copyright 2019 PingCAP, Inc.
+
+//! Proto files which are supplied as strings rather than found on disk, e.g.,
+//! because a build script templates them.
+//!
+//! For generation, they are written to a managed include directory, which comes
+//! before all other includes: `$OUT_DIR/proto-sources` in a build script, a
+//! temporary directory otherwise.
+
+use std::env::var;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::Builder;
+
+impl Builder {
+    /// Add a proto file named `name` (relative to an include, e.g.,
+    /// `gen/config.proto`) with the given contents. Other files can import it
+    /// by that name, and it is generated if `name` is passed to `files` or
+    /// `roots`. Adding a name again replaces its contents.
+    pub fn add_proto_source(
+        &mut self,
+        name: impl Into<String>,
+        contents: impl Into<String>,
+    ) -> &mut Self {
+        let name = name.into();
+        assert!(
+            Path::new(&name).is_relative() && !name.split('/').any(|c| c == ".."),
+            "proto source `{}` must be named relative to an include",
+            name
+        );
+        let contents = contents.into();
+        match self.proto_sources.iter_mut().find(|(n, _)| *n == name) {
+            Some(source) => source.1 = contents,
+            None => self.proto_sources.push((name, contents)),
+        }
+        self
+    }
+
+    /// The include directory for the proto sources of a build script, or
+    /// `None` if it must be temporary.
+    pub(crate) fn proto_source_root() -> Option<PathBuf> {
+        var("OUT_DIR")
+            .ok()
+            .map(|d| Path::new(&d).join("proto-sources"))
+    }
+
+    /// A copy of `self` which generates from the proto sources written to
+    /// `root`.
+    pub(crate) fn with_proto_sources(&self, root: &Path) -> Builder {
+        let mut builder = self.clone();
+        write_sources(root, &self.proto_sources);
+        builder.includes.insert(0, root.display().to_string());
+        builder.proto_source_dir = Some(root.to_owned());
+        builder
+    }
+
+    /// The path of the file `file` passed to `files`: in the proto source
+    /// directory if it names a proto source.
+    pub(crate) fn source_path(&self, file: &str) -> PathBuf {
+        match &self.proto_source_dir {
+            Some(root) if self.proto_sources.iter().any(|(n, _)| n == file) => root.join(file),
+            _ => PathBuf::from(file),
+        }
+    }
+}
+
+// Makes `root` contain exactly `sources`. Unchanged files are not written
+// again, so that they don't trigger `rerun-if-changed`.
+fn write_sources(root: &Path, sources: &[(String, String)]) {
+    let mut dirs = vec![root.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for e in entries {
+            let path = e.expect("Couldn't list file").path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if !sources.iter().any(|(n, _)| root.join(n) == path) {
+                fs::remove_file(&path).expect("Couldn't remove stale proto source");
+            }
+        }
+    }
+    for (name, contents) in sources {
+        let path = root.join(name);
+        if fs::read_to_string(&path).ok().as_ref() == Some(contents) {
+            continue;
+        }
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents)
+            .unwrap_or_else(|e| panic!("Couldn't write proto source {}: {}", name, e));
+    }
+}
+
+#[cfg(all(test, feature = "protobuf-codec"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_proto_sources() {
+        let files = Builder::new()
+            .includes(&["tests/proto"])
+            .add_proto_source("gen/config.proto", "syntax = \"proto3\";")
+            .add_proto_source(
+                "gen/config.proto",
+                "syntax = \"proto3\";\nimport \"nested.proto\";\nmessage Config { nested.Event event = 1; }",
+            )
+            .files(&["gen/config.proto"])
+            .generate_to_memory();
+        let config = &files[Path::new("config.rs")];
+        assert!(config.contains("pub struct Config"), "{}", config);
+        assert!(config.contains("super::nested::Event"), "{}", config);
+    }
+}
@@ -89,19 +89,18 @@ fn generate_struct<W>(
 where
     W: Write,
 {
+    let methods = field_methods(item, prefix);
+    if let Err(e) = check_collisions(&methods, gen_opt) {
+        panic!("message `{}{}`: {}", prefix, item.ident, e);
+    }
+
     writeln!(buf, "impl {}{} {{", prefix, item.ident)?;
     if gen_opt.contains(GenOpt::NEW) {
         generate_new(&item.ident, prefix, buf)?;
     }
     generate_default_ref(&item.ident, prefix, gen_opt, buf)?;
-    item.fields
+    methods
         .iter()
-        .filter_map(|f| {
-            f.ident
-                .as_ref()
-                .map(|i| (i, &f.ty, FieldKind::from_attrs(&f.attrs, prefix)))
-        })
-        .filter_map(|(n, t, (k, deprecated))| k.methods(t, n, deprecated))
         .map(|m| m.write_methods(buf, gen_opt))
         .collect::<Result<Vec<_>, _>>()?;
     writeln!(buf, "}}")?;
@@ -111,6 +110,61 @@ where
     Ok(())
 }
 
+fn field_methods(item: &ItemStruct, prefix: &str) -> Vec<FieldMethods> {
+    item.fields
+        .iter()
+        .filter_map(|f| {
+            let ident = f.ident.as_ref()?;
+            let (kind, deprecated) = FieldKind::from_attrs(&f.attrs, prefix);
+            let mut methods = kind.methods(&f.ty, ident, deprecated)?;
+            methods.prost_methods = prost_method_names(&f.attrs, &methods.unesc_base);
+            Some(methods)
+        })
+        .collect()
+}
+
+/// Checks that no two fields get a method with the same name, and that no field
+/// gets a method named like one of the message's own (`new_` and `default_ref`).
+/// The methods of a field are the ones `write_methods` writes and the ones
+/// prost generates itself, so, e.g., an enum field `get_x` collides with the
+/// getter of a field `x`.
+fn check_collisions(methods: &[FieldMethods], gen_opt: GenOpt) -> Result<(), String> {
+    let mut names: Vec<(String, Option<&str>)> = vec![("default_ref".to_owned(), None)];
+    if gen_opt.contains(GenOpt::NEW) {
+        names.push(("new_".to_owned(), None));
+    }
+    for m in methods {
+        for name in m
+            .method_names(gen_opt)
+            .into_iter()
+            .chain(m.prost_methods.clone())
+        {
+            match names.iter().find(|(n, _)| *n == name) {
+                Some((_, Some(other))) if *other == m.unesc_base => {
+                    return Err(format!(
+                        "field `{}` needs a method named `{}`, which prost already generates for it",
+                        m.name, name
+                    ))
+                }
+                Some((_, Some(other))) => {
+                    return Err(format!(
+                        "fields `{}` and `{}` both need a method named `{}`, rename one of them",
+                        other, m.name, name
+                    ))
+                }
+                Some((_, None)) => {
+                    return Err(format!(
+                        "field `{}` needs a method named `{}`, which the message already has, rename the field",
+                        m.name, name
+                    ))
+                }
+                None => names.push((name, Some(&m.unesc_base))),
+            }
+        }
+    }
+    Ok(())
+}
+
 fn generate_enum<W>(item: &ItemEnum, prefix: &str, buf: &mut W) -> Result<(), io::Error>
 where
     W: Write,
@@ -249,6 +303,50 @@ where
     writeln!(buf, "}}")
 }
 
+/// The names of the methods prost generates for a field, given its `prost`
+/// attribute: a getter named after the field for enums and optional scalars,
+/// `set_` for enums, `push_` for repeated enums, and `get_` and `insert_` for
+/// maps with enum values.
+fn prost_method_names(attrs: &[Attribute], name: &str) -> Vec<String> {
+    let (mut optional, mut repeated, mut message, mut enumeration, mut enum_map) =
+        (false, false, false, false, false);
+    for a in attrs.iter().filter(|a| a.path.is_ident("prost")) {
+        if let Ok(Meta::List(list)) = a.parse_meta() {
+            for item in &list.nested {
+                match item {
+                    NestedMeta::Meta(Meta::Path(id)) => {
+                        optional |= id.is_ident("optional");
+                        repeated |= id.is_ident("repeated");
+                        message |= id.is_ident("message") || id.is_ident("group");
+                    }
+                    NestedMeta::Meta(Meta::NameValue(mnv)) => {
+                        let value = mnv.lit.clone().into_token_stream().to_string();
+                        enumeration |= mnv.path.is_ident("enumeration");
+                        enum_map |= mnv.path.is_ident("map") && value.contains("enumeration");
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    let names: &[&str] = if enum_map {
+        &["get_", "insert_"]
+    } else if enumeration && repeated {
+        &["", "push_"]
+    } else if enumeration {
+        &["", "set_"]
+    } else if optional && !message {
+        &[""]
+    } else {
+        &[]
+    };
+    names
+        .iter()
+        .map(|prefix| format!("{}{}", prefix, name))
+        .collect()
+}
+
 const INT_TYPES: [&str; 4] = ["int32", "int64", "uint32", "uint64"];
 
 #[derive(Clone, Eq, PartialEq, Debug, Ord, PartialOrd)]
@@ -577,6 +675,8 @@ struct FieldMethods {
     mt: MethodKind,
     take: Option<String>,
     deprecated: bool,
+    // The methods prost generates itself.
+    prost_methods: Vec<String>,
 }
 
 impl FieldMethods {
@@ -599,9 +699,40 @@ impl FieldMethods {
             mt: MethodKind::None,
             take: None,
             deprecated,
+            prost_methods: Vec::new(),
         }
     }
 
+    /// The names of the methods `write_methods` writes.
+    fn method_names(&self, gen_opt: GenOpt) -> Vec<String> {
+        let set = match &self.set {
+            Some(_) => !self.enum_set,
+            None => gen_opt.contains(GenOpt::TRIVIAL_SET),
+        };
+        let methods = [
+            ("has", self.has && gen_opt.contains(GenOpt::HAS)),
+            ("clear", gen_opt.contains(GenOpt::CLEAR)),
+            ("set", set),
+            (
+                "get",
+                self.get.is_some() || gen_opt.contains(GenOpt::TRIVIAL_GET),
+            ),
+            (
+                "mut",
+                gen_opt.contains(GenOpt::MUT) && !matches!(self.mt, MethodKind::None),
+            ),
+            (
+                "take",
+                gen_opt.contains(GenOpt::TAKE) && self.take.is_some(),
+            ),
+        ];
+        methods
+            .iter()
+            .filter(|(_, written)| *written)
+            .map(|(prefix, _)| format!("{}_{}", prefix, self.unesc_base))
+            .collect()
+    }
+
     fn write_methods<W>(&self, buf: &mut W, gen_opt: GenOpt) -> Result<(), io::Error>
     where
         W: Write,
@@ -757,7 +888,7 @@ fn type_in_expr_context(s: &str) -> String {
     let last_segment = parsed.path.segments.last_mut().unwrap();
     if !last_segment.arguments.is_empty() {
         if let PathArguments::AngleBracketed(ref mut a) = last_segment.arguments {
-            if a.colon2_token == None {
+            if a.colon2_token.is_none() {
                 a.colon2_token = Some(Token![::](Span::call_site()));
             }
         }
@@ -769,6 +900,85 @@ fn type_in_expr_context(s: &str) -> String {
 mod test {
     use super::*;
 
+    // What prost generates for
+    // `message M { uint64 x = 1; Kind get_x = 2; repeated Kind kinds = 3; }`.
+    const MESSAGE: &str = "
+        #[derive(Clone, PartialEq, ::prost::Message)]
+        pub struct M {
+            #[prost(uint64, tag = \"1\")]
+            pub x: u64,
+            #[prost(enumeration = \"Kind\", tag = \"2\")]
+            pub get_x: i32,
+            #[prost(enumeration = \"Kind\", repeated, tag = \"3\")]
+            pub kinds: ::prost::alloc::vec::Vec<i32>,
+        }";
+
+    fn parse(message: &str) -> Vec<FieldMethods> {
+        field_methods(&syn::parse_str(message).unwrap(), "")
+    }
+
+    #[test]
+    fn test_collisions() {
+        let mut methods = parse(MESSAGE);
+        assert_eq!(methods[1].prost_methods, vec!["get_x", "set_get_x"]);
+        assert_eq!(methods[2].prost_methods, vec!["kinds", "push_kinds"]);
+        assert_eq!(
+            check_collisions(&methods, GenOpt::all()),
+            Err(
+                "fields `x` and `get_x` both need a method named `get_x`, rename one of them"
+                    .to_owned()
+            )
+        );
+        methods.remove(0);
+        assert_eq!(check_collisions(&methods, GenOpt::all()), Ok(()));
+
+        let methods = parse(&MESSAGE.replace("get_x", "default_ref"));
+        assert_eq!(
+            check_collisions(&methods, GenOpt::all()),
+            Err("field `default_ref` needs a method named `default_ref`, which the message already has, rename the field".to_owned())
+        );
+
+        // A map of enums gets a prost getter named like the wrapper's.
+        let methods = parse(
+            "#[derive(Clone, PartialEq, ::prost::Message)]
+            pub struct Request {
+                #[prost(string, optional, tag = \"1\")]
+                pub key: ::core::option::Option<::prost::alloc::string::String>,
+                #[prost(map = \"string, enumeration(Kind)\", tag = \"2\")]
+                pub kinds: ::std::collections::HashMap<::prost::alloc::string::String, i32>,
+            }",
+        );
+        assert_eq!(methods[0].prost_methods, vec!["key"]);
+        assert_eq!(methods[1].prost_methods, vec!["get_kinds", "insert_kinds"]);
+        assert_eq!(
+            check_collisions(&methods, GenOpt::all() - GenOpt::TRIVIAL_GET),
+            Ok(())
+        );
+        assert_eq!(
+            check_collisions(&methods, GenOpt::all()),
+            Err(
+                "field `kinds` needs a method named `get_kinds`, which prost already generates for it"
+                    .to_owned()
+            )
+        );
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "message `M`: fields `x` and `get_x` both need a method named `get_x`"
+    )]
+    fn test_generated_collisions() {
+        crate::Builder::new()
+            .add_proto_source(
+                "m.proto",
+                "syntax = \"proto3\";
+                enum Kind { UNKNOWN = 0; }
+                message M { uint64 x = 1; Kind get_x = 2; }",
+            )
+            .files(&["m.proto"])
+            .generate_to_memory();
+    }
+
     #[test]
     fn test_type_in_expr_context() {
         assert_eq!("T", type_in_expr_context("T"));
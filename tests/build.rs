@@ -3,5 +3,23 @@
 use protobuf_build::Builder;
 
 fn main() {
-    Builder::new().search_dir_for_protos("proto").generate()
+    Builder::new().search_dir_for_protos("proto").generate();
+
+    // `enums.proto` with the other unknown enum policies, for the tests in
+    // `lib.rs`. Only rust-protobuf has the option. Which codec protobuf-build
+    // was built with isn't known from this crate's features, as `--features`
+    // only applies to the root crate, so the option is set by name.
+    println!("cargo:rustc-check-cfg=cfg(unknown_enum_policy)");
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    for policy in &["preserve_in_unknown_fields", "default"] {
+        let mut builder = Builder::new();
+        if builder.set_option("unknown_enum_policy", policy).is_err() {
+            return;
+        }
+        builder
+            .files(&["proto/enums.proto"])
+            .out_dir(format!("{}/{}", out_dir, policy))
+            .generate();
+    }
+    println!("cargo:rustc-cfg=unknown_enum_policy");
 }
@@ -1,11 +1,60 @@
 // Copyright 2019 PingCAP, Inc.
 
-#[allow(dead_code)]
-#[allow(unknown_lints)]
-#[allow(clippy::all)]
-#[allow(renamed_and_removed_lints)]
-#[allow(bare_trait_objects)]
-
 mod protos {
     include!(concat!(env!("OUT_DIR"), "/protos/mod.rs"));
 }
+
+#[cfg(unknown_enum_policy)]
+mod preserve_in_unknown_fields {
+    include!(concat!(
+        env!("OUT_DIR"),
+        "/preserve_in_unknown_fields/mod.rs"
+    ));
+}
+
+#[cfg(unknown_enum_policy)]
+mod default {
+    include!(concat!(env!("OUT_DIR"), "/default/mod.rs"));
+}
+
+#[cfg(all(test, unknown_enum_policy))]
+mod test {
+    use protobuf::Message;
+
+    // A `Paint` with `color: 2` and `colors: [RED, 2]`, as encoded by a newer
+    // version of `enums.proto` with another value of `Color`.
+    const NEWER: &[u8] = &[0x08, 0x02, 0x12, 0x02, 0x01, 0x02];
+
+    #[test]
+    fn test_unknown_enum_error() {
+        use super::protos::enums::Paint;
+
+        assert!(Paint::parse_from_bytes(NEWER).is_err());
+    }
+
+    #[test]
+    fn test_unknown_enum_preserve_in_unknown_fields() {
+        use super::preserve_in_unknown_fields::enums::{Color, Paint};
+
+        let paint = Paint::parse_from_bytes(NEWER).unwrap();
+        assert_eq!(paint.get_color(), Color::UNKNOWN);
+        assert_eq!(paint.get_colors(), &[Color::RED]);
+        let unknown: Vec<_> = paint.get_unknown_fields().iter().collect();
+        assert_eq!(unknown.len(), 2);
+
+        // The values survive re-encoding.
+        let bytes = paint.write_to_bytes().unwrap();
+        let paint = Paint::parse_from_bytes(&bytes).unwrap();
+        assert_eq!(paint.get_unknown_fields().iter().count(), 2);
+    }
+
+    #[test]
+    fn test_unknown_enum_default() {
+        use super::default::enums::{Color, Paint};
+
+        let paint = Paint::parse_from_bytes(NEWER).unwrap();
+        assert_eq!(paint.get_color(), Color::UNKNOWN);
+        assert_eq!(paint.get_colors(), &[Color::RED, Color::UNKNOWN]);
+        assert!(paint.get_unknown_fields().iter().next().is_none());
+    }
+}